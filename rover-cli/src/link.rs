@@ -0,0 +1,126 @@
+//!serial link to the rover
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use rover_protocol::{Decoder, DecodeError, Frame, Reply, Request, Scan, Telemetry, MAX_FRAME};
+use serialport::SerialPort;
+
+///how long to wait for the reply to a request
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct Link {
+    port: Box<dyn SerialPort>,
+    decoder: Decoder,
+    seq: u8,
+}
+
+impl Link {
+    ///open a serial device or pty
+    pub fn open(path: &str, baud: u32) -> serialport::Result<Self> {
+        let port = serialport::new(path, baud)
+            .timeout(Duration::from_millis(10))
+            .open()?;
+
+        Ok(Link { port, decoder: Decoder::new(), seq: 0 })
+    }
+
+    ///send a request, returns the sequence number it was sent with
+    pub fn send(&mut self, request: &Request) -> io::Result<u8> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut buf = [0; MAX_FRAME];
+        let len = request.frame().with_seq(seq).encode(&mut buf);
+        self.port.write_all(&buf[..len])?;
+        self.port.flush()?;
+
+        Ok(seq)
+    }
+
+    ///send a request and wait for its reply
+    ///frames arriving in the meantime are handed to `other`
+    pub fn request(&mut self, request: &Request, mut other: impl FnMut(&Frame)) -> io::Result<Option<Reply>> {
+        let seq = self.send(request)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match self.next_frame(remaining)? {
+                Some(Ok(frame)) => match Reply::from_frame(&frame) {
+                    Some((reply, s)) if s == seq => return Ok(Some(reply)),
+                    _ => other(&frame),
+                },
+                Some(Err(e)) => eprintln!("bad frame from rover: {:?}", e),
+                None => {},
+            }
+        }
+
+        Ok(None)
+    }
+
+    ///read until a whole frame has been decoded or the timeout expires
+    pub fn next_frame(&mut self, timeout: Duration) -> io::Result<Option<Result<Frame, DecodeError>>> {
+        let deadline = Instant::now() + timeout;
+        let mut byte = [0; 1];
+
+        loop {
+            match self.port.read(&mut byte) {
+                Ok(1) => {
+                    if let Some(result) = self.decoder.feed(byte[0]) {
+                        return Ok(Some(result));
+                    }
+                },
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(e),
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+///human readable form of a frame sent by the rover
+pub fn describe(frame: &Frame) -> String {
+    if let Some((reply, seq)) = Reply::from_frame(frame) {
+        return format!("reply to #{}: {:?}", seq, reply);
+    }
+
+    if let Some(t) = Telemetry::from_frame(frame) {
+        let distance = match t.distance {
+            Some(d) => format!("{} cm", d),
+            None => "-".to_string(),
+        };
+
+        let link = match t.link_timeout_ms {
+            0 => "failsafe off".to_string(),
+            timeout => format!("{}/{} ms", t.link_age_ms, timeout),
+        };
+
+        return format!(
+            "[{}.{:03} s] {} distance {} sensor {:?} command {:?} link {}",
+            t.uptime_ms / 1000,
+            t.uptime_ms % 1000,
+            if t.auto { "auto" } else { "manual" },
+            distance,
+            t.position,
+            t.command,
+            link,
+        );
+    }
+
+    if let Some(scan) = Scan::from_frame(frame) {
+        let points = scan
+            .points()
+            .iter()
+            .map(|(angle, cm)| format!("{}°: {} cm", angle, cm))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return format!("scan {}/{} {}", usize::from(scan.index) + scan.points().len(), scan.count, points);
+    }
+
+    format!("frame id {:#04x} payload {:02x?}", frame.id, frame.payload())
+}
@@ -0,0 +1,157 @@
+//!host cli for driving the rover over a serial port
+//!sends the same frames as the firmware decodes, prints replies and telemetry
+
+mod link;
+mod teleop;
+
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use rover_protocol::{Command, Request, Reply, FULL_SPEED, TURN_MS, BRAKE_MS};
+
+use link::Link;
+
+#[derive(Parser)]
+#[command(about = "Drive the obstacle avoiding rover over a serial port")]
+struct Cli {
+    ///serial device or pty, e.g. /dev/rfcomm0
+    port: String,
+
+    #[arg(short, long, default_value_t = 9600)]
+    baud: u32,
+
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    ///send a single command and print the reply
+    Send {
+        #[command(subcommand)]
+        command: Cmd,
+    },
+    ///print telemetry and replies as they arrive
+    Monitor,
+    ///drive the rover from the keyboard
+    Teleop,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    ///toggle auto/manual mode
+    Auto,
+    Forward {
+        #[arg(short, long, default_value_t = FULL_SPEED)]
+        speed: u8,
+        ///0 runs until the next command
+        #[arg(short, long, default_value_t = 0)]
+        duration_ms: u16,
+    },
+    Reverse {
+        #[arg(short, long, default_value_t = FULL_SPEED)]
+        speed: u8,
+        ///0 runs until the next command
+        #[arg(short, long, default_value_t = 0)]
+        duration_ms: u16,
+    },
+    Right {
+        #[arg(short, long, default_value_t = TURN_MS)]
+        duration_ms: u16,
+    },
+    Left {
+        #[arg(short, long, default_value_t = TURN_MS)]
+        duration_ms: u16,
+    },
+    Brake {
+        #[arg(short, long, default_value_t = BRAKE_MS)]
+        duration_ms: u16,
+    },
+    Stop,
+    Donut {
+        #[arg(short, long, default_value_t = 1)]
+        spins: u8,
+    },
+    ///set the telemetry period, 0 turns it off
+    TelemetryRate {
+        ms: u16,
+    },
+    ///keep the link alive
+    Heartbeat,
+    ///stop manual motion after this many ms without a frame, 0 turns the failsafe off
+    LinkTimeout {
+        ms: u16,
+    },
+}
+
+impl From<Cmd> for Request {
+    fn from(cmd: Cmd) -> Self {
+        match cmd {
+            Cmd::Auto => Request::Auto,
+            Cmd::Forward { speed, duration_ms } => Request::Drive(Command::Forward { speed, duration_ms }),
+            Cmd::Reverse { speed, duration_ms } => Request::Drive(Command::Reverse { speed, duration_ms }),
+            Cmd::Right { duration_ms } => Request::Drive(Command::RightTurn { duration_ms }),
+            Cmd::Left { duration_ms } => Request::Drive(Command::LeftTurn { duration_ms }),
+            Cmd::Brake { duration_ms } => Request::Drive(Command::Brake { duration_ms }),
+            Cmd::Stop => Request::Drive(Command::Stop),
+            Cmd::Donut { spins } => Request::Drive(Command::Donut { spins }),
+            Cmd::TelemetryRate { ms } => Request::TelemetryRate(ms),
+            Cmd::Heartbeat => Request::Heartbeat,
+            Cmd::LinkTimeout { ms } => Request::LinkTimeout(ms),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let mut link = match Link::open(&cli.port, cli.baud) {
+        Ok(link) => link,
+        Err(e) => {
+            eprintln!("cannot open {}: {}", cli.port, e);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let result = match cli.action {
+        Action::Send { command } => send(&mut link, command.into()),
+        Action::Monitor => monitor(&mut link),
+        Action::Teleop => teleop::run(&mut link).map(|_| ExitCode::SUCCESS),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn send(link: &mut Link, request: Request) -> std::io::Result<ExitCode> {
+    match link.request(&request, |frame| println!("{}", link::describe(frame)))? {
+        Some(Reply::Accepted) => {
+            println!("{:?}: accepted", request);
+            Ok(ExitCode::SUCCESS)
+        },
+        Some(reply) => {
+            println!("{:?}: {:?}", request, reply);
+            Ok(ExitCode::FAILURE)
+        },
+        None => {
+            println!("{:?}: no reply", request);
+            Ok(ExitCode::FAILURE)
+        },
+    }
+}
+
+fn monitor(link: &mut Link) -> std::io::Result<ExitCode> {
+    loop {
+        match link.next_frame(Duration::from_secs(1))? {
+            Some(Ok(frame)) => println!("{}", link::describe(&frame)),
+            Some(Err(e)) => eprintln!("bad frame from rover: {:?}", e),
+            None => {},
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//!interactive keyboard teleop
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::terminal;
+use rover_protocol::{Command, Reply, Request, FULL_SPEED, TURN_MS, BRAKE_MS};
+
+use crate::link::{self, Link};
+
+///speed change per +/- key press, %
+const SPEED_STEP: u8 = 10;
+///keeps the rover's link-loss failsafe from tripping while teleop is idle
+const HEARTBEAT: Duration = Duration::from_millis(300);
+
+const HELP: &str = "w/up forward, s/down reverse, a/left left, d/right right, \
+space stop, b brake, o donut, m auto/manual, +/- speed, q/esc quit";
+
+///restores the terminal even if teleop bails out early
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub fn run(link: &mut Link) -> io::Result<()> {
+    println!("{}", HELP);
+    let _raw = RawMode::enable()?;
+    let mut speed = FULL_SPEED;
+    let mut last_sent = Instant::now();
+    let mut heartbeat_seq = None;
+
+    loop {
+        if event::poll(Duration::from_millis(20))? {
+            if let Event::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }) = event::read()? {
+                let request = match code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char('w') | KeyCode::Up => Request::Drive(Command::Forward { speed, duration_ms: 0 }),
+                    KeyCode::Char('s') | KeyCode::Down => Request::Drive(Command::Reverse { speed, duration_ms: 0 }),
+                    KeyCode::Char('a') | KeyCode::Left => Request::Drive(Command::LeftTurn { duration_ms: TURN_MS }),
+                    KeyCode::Char('d') | KeyCode::Right => Request::Drive(Command::RightTurn { duration_ms: TURN_MS }),
+                    KeyCode::Char(' ') => Request::Drive(Command::Stop),
+                    KeyCode::Char('b') => Request::Drive(Command::Brake { duration_ms: BRAKE_MS }),
+                    KeyCode::Char('o') => Request::Drive(Command::DONUT),
+                    KeyCode::Char('m') => Request::Auto,
+                    KeyCode::Char('+') => {
+                        speed = speed.saturating_add(SPEED_STEP).min(FULL_SPEED);
+                        print!("speed {}%\r\n", speed);
+                        continue;
+                    },
+                    KeyCode::Char('-') => {
+                        speed = speed.saturating_sub(SPEED_STEP);
+                        print!("speed {}%\r\n", speed);
+                        continue;
+                    },
+                    _ => continue,
+                };
+
+                let seq = link.send(&request)?;
+                last_sent = Instant::now();
+                print!("#{} {:?}\r\n", seq, request);
+            }
+        }
+
+        if last_sent.elapsed() >= HEARTBEAT {
+            heartbeat_seq = Some(link.send(&Request::Heartbeat)?);
+            last_sent = Instant::now();
+        }
+
+        //show replies and telemetry as they come in
+        while let Some(result) = link.next_frame(Duration::ZERO)? {
+            match result {
+                Ok(frame) => {
+                    match Reply::from_frame(&frame) {
+                        Some((Reply::Accepted, seq)) if Some(seq) == heartbeat_seq => {},//not worth showing
+                        _ => print!("{}\r\n", link::describe(&frame)),
+                    }
+                },
+                Err(e) => print!("bad frame from rover: {:?}\r\n", e),
+            }
+        }
+    }
+
+    Ok(())
+}
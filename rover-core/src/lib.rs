@@ -0,0 +1,1703 @@
+//!hardware independent rover logic
+//!builds for the host as well as the board, the firmware supplies the peripherals
+
+#![no_std]
+
+pub mod distance {
+    //!echo timing to distance
+    use core::fmt;
+
+    ///echo timer counts between overflows, arr 0xFFFF at 1 count per us
+    pub const TIMER_PERIOD: u32 = 0x1_0000;
+
+    ///length of the echo pulse, us
+    ///t1 and t2 are the captures at the rising and falling edge, overflows the timer overflows in between
+    pub fn pulse_us(t1: u32, t2: u32, overflows: u32) -> u32 {
+        let ticks = u64::from(overflows) * u64::from(TIMER_PERIOD) + u64::from(t2);
+        ticks.saturating_sub(u64::from(t1)).min(u64::from(u32::MAX)) as u32
+    }
+
+    ///distance with millimetre resolution
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+    pub struct Distance(u32);
+
+    impl Distance {
+        pub const ZERO: Distance = Distance(0);
+        pub const MAX: Distance = Distance(u32::MAX);
+
+        pub const fn from_mm(mm: u32) -> Self {
+            Distance(mm)
+        }
+
+        ///saturates at MAX
+        pub const fn from_cm(cm: u32) -> Self {
+            Distance(cm.saturating_mul(10))
+        }
+
+        pub const fn mm(self) -> u32 {
+            self.0
+        }
+
+        ///whole centimetres, rounded down
+        pub const fn cm(self) -> u32 {
+            self.0 / 10
+        }
+
+        pub const fn abs_diff(self, other: Distance) -> Distance {
+            Distance(self.0.abs_diff(other.0))
+        }
+
+        pub const fn saturating_add(self, other: Distance) -> Distance {
+            Distance(self.0.saturating_add(other.0))
+        }
+
+        pub const fn saturating_sub(self, other: Distance) -> Distance {
+            Distance(self.0.saturating_sub(other.0))
+        }
+    }
+
+    ///centimetres to one decimal, `23.4 cm`
+    impl fmt::Display for Distance {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}.{} cm", self.0 / 10, self.0 % 10)
+        }
+    }
+
+    ///longest echo pulse, the hc-sr04 holds echo high this long when nothing answers, us
+    pub const ECHO_TIMEOUT_US: u32 = 38_000;
+    ///furthest the hc-sr04 measures reliably
+    pub const MAX_RANGE: Distance = Distance::from_cm(400);
+
+    ///outcome of one ping
+    ///ordered nearest first, so anything out of range sorts after every distance and a missing echo last
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Range {
+        Valid(Distance),
+        OutOfRange,//echo came back, nothing within MAX_RANGE
+        NoEcho,//no echo before the deadline, the sensor is unplugged or faulty
+    }
+
+    impl Range {
+        pub fn distance(&self) -> Option<Distance> {
+            match self {
+                Range::Valid(d) => Some(*d),
+                _ => None,
+            }
+        }
+    }
+
+    impl fmt::Display for Range {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Range::Valid(d) => write!(f, "{}", d),
+                Range::OutOfRange => f.write_str("out of range"),
+                Range::NoEcho => f.write_str("no echo"),
+            }
+        }
+    }
+
+    ///air temperature assumed when there is no reading, tenths of a degree C
+    pub const FALLBACK_DC: i16 = 200;
+
+    ///something that knows the air temperature, a sensor or a value set by hand
+    pub trait TemperatureSource {
+        ///air temperature, tenths of a degree C. None if there is no reading right now
+        fn temperature_dc(&mut self) -> Option<i16>;
+
+        ///relative humidity, %. Most sensors only give temperature
+        fn humidity_pct(&mut self) -> Option<u8> {
+            None
+        }
+    }
+
+    ///conditions that do not change, for boards without a sensor
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Fixed {
+        pub temperature_dc: i16,
+        pub humidity_pct: Option<u8>,
+    }
+
+    impl Fixed {
+        pub const FALLBACK: Fixed = Fixed { temperature_dc: FALLBACK_DC, humidity_pct: None };
+    }
+
+    impl TemperatureSource for Fixed {
+        fn temperature_dc(&mut self) -> Option<i16> {
+            Some(self.temperature_dc)
+        }
+
+        fn humidity_pct(&mut self) -> Option<u8> {
+            self.humidity_pct
+        }
+    }
+
+    ///speed of sound in air, turns echo pulses into distance
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SpeedOfSound {
+        mm_per_s: u32,
+    }
+
+    impl Default for SpeedOfSound {
+        fn default() -> Self {
+            Self::FALLBACK
+        }
+    }
+
+    impl SpeedOfSound {
+        ///dry air at the fallback temperature, 343 m/s
+        pub const FALLBACK: SpeedOfSound = SpeedOfSound::new(FALLBACK_DC, None);
+
+        ///331.3 m/s at 0C plus 0.606 m/s per degree. Moist air is a little faster, about 0.0124 m/s per % rh
+        ///temperature is clamped to -40..85C, what the hc-sr04 is rated for
+        pub const fn new(temperature_dc: i16, humidity_pct: Option<u8>) -> Self {
+            let dc = if temperature_dc < -400 { -400 } else if temperature_dc > 850 { 850 } else { temperature_dc as i32 };
+            let rh = match humidity_pct {
+                Some(rh) if rh > 100 => 100,
+                Some(rh) => rh as i32,
+                None => 0,
+            };
+
+            SpeedOfSound { mm_per_s: (331_300 + 606 * dc / 10 + 124 * rh / 10) as u32 }
+        }
+
+        ///conditions from a source, the fallback if it has no reading
+        pub fn from_source(source: &mut impl TemperatureSource) -> Self {
+            match source.temperature_dc() {
+                Some(dc) => Self::new(dc, source.humidity_pct()),
+                None => Self::FALLBACK,
+            }
+        }
+
+        pub fn mm_per_s(&self) -> u32 {
+            self.mm_per_s
+        }
+
+        ///distance to the obstacle for an echo pulse lasting ticks of a tick_hz timer
+        ///the pulse spans the trip out and back. Integer only, safe to call from interrupts
+        pub fn echo_distance(&self, ticks: u32, tick_hz: u32) -> Distance {
+            let mm = u64::from(ticks) * u64::from(self.mm_per_s) / (2 * u64::from(tick_hz));
+            Distance::from_mm(mm.min(u64::from(u32::MAX)) as u32)
+        }
+
+        ///echo pulse in us as a range reading
+        pub fn range(&self, pulse_us: u32) -> Range {
+            match self.echo_distance(pulse_us, 1_000_000) {
+                d if d > MAX_RANGE => Range::OutOfRange,
+                d => Range::Valid(d),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn pulse_within_one_period() {
+            assert_eq!(pulse_us(100, 1_100, 0), 1_000);
+        }
+
+        #[test]
+        fn pulse_across_overflows() {
+            assert_eq!(pulse_us(0xFF00, 0x0100, 1), 0x200);//falling edge just after the wrap
+            assert_eq!(pulse_us(10, 10, 2), 2 * TIMER_PERIOD);
+        }
+
+        #[test]
+        fn pulse_never_negative() {
+            assert_eq!(pulse_us(500, 100, 0), 0);//overflow counted before the rising edge was cleared
+        }
+
+        #[test]
+        fn pulse_saturates() {
+            assert_eq!(pulse_us(0, 0, u32::MAX), u32::MAX);
+        }
+    }
+}
+
+pub mod ranging {
+    //!several echoes per sensor position reduced to one distance
+    //!the median is taken and readings far from it are dropped as outliers
+    use crate::distance::{Distance, Range};
+
+    ///echoes taken per sensor position
+    pub const SAMPLES: usize = 3;
+    ///readings within this of the median agree with it
+    pub const TOLERANCE: Distance = Distance::from_cm(3);
+
+    ///a filtered reading
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Filtered {
+        pub range: Range,//the median, distances averaged over the readings that agree with it
+        pub confidence: u8,//share of readings that agree, percent
+        pub valid: bool,//more than half agree
+    }
+
+    ///reduce a set of readings, None if there are none. Sorts the readings
+    ///out of range and missing echoes only agree with their own kind
+    pub fn filter(readings: &mut [Range]) -> Option<Filtered> {
+        if readings.is_empty() {
+            return None;
+        }
+        readings.sort_unstable();
+
+        let median = readings[(readings.len() - 1) / 2];
+        let (range, agreeing) = match median {
+            Range::Valid(median) => {
+                let tolerance = TOLERANCE.max(Distance::from_mm(median.mm() / 10));//hc-sr04 is good to a few percent further out
+                let (sum, agreeing) = readings
+                    .iter()
+                    .filter_map(Range::distance)
+                    .filter(|d| d.abs_diff(median) <= tolerance)
+                    .fold((0u64, 0usize), |(sum, n), d| (sum + u64::from(d.mm()), n + 1));
+                let mean = (sum + agreeing as u64 / 2) / agreeing as u64;
+                (Range::Valid(Distance::from_mm(mean as u32)), agreeing)
+            },
+            other => (other, readings.iter().filter(|r| **r == other).count()),
+        };
+
+        Some(Filtered {
+            range,
+            confidence: (agreeing * 100 / readings.len()) as u8,
+            valid: agreeing * 2 > readings.len(),
+        })
+    }
+
+    ///collects N readings at one sensor position
+    pub struct Ranging<const N: usize> {
+        readings: [Range; N],
+        len: usize,
+    }
+
+    impl<const N: usize> Default for Ranging<N> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<const N: usize> Ranging<N> {
+        pub const fn new() -> Self {
+            Ranging { readings: [Range::NoEcho; N], len: 0 }
+        }
+
+        ///add a reading. Returns the filtered range and starts over once N are in
+        pub fn push(&mut self, range: Range) -> Option<Filtered> {
+            if N == 0 {
+                return None;
+            }
+
+            self.readings[self.len] = range;
+            self.len += 1;
+            if self.len < N {
+                return None;
+            }
+
+            self.len = 0;
+            filter(&mut self.readings)
+        }
+
+        ///drop readings so far, the sensor moved
+        pub fn clear(&mut self) {
+            self.len = 0;
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::distance::Range::{NoEcho, OutOfRange, Valid};
+
+        const fn mm(mm: u32) -> Range {
+            Valid(Distance::from_mm(mm))
+        }
+
+        //recorded on the bench, wall at about 80 cm
+        const WALL: [Range; 5] = [mm(801), mm(797), mm(2314), mm(803), mm(799)];//one echo off the ceiling
+        const WALL_MISSED: [Range; 3] = [mm(802), NoEcho, NoEcho];//sensor knocked during the burst
+        const OPEN_FIELD: [Range; 3] = [OutOfRange, mm(3890), OutOfRange];
+
+        fn filtered<const N: usize>(mut readings: [Range; N]) -> Filtered {
+            filter(&mut readings).unwrap()
+        }
+
+        #[test]
+        fn outlier_rejected() {
+            let wall = filtered(WALL);
+            assert_eq!(wall.range, mm(800));
+            assert_eq!(wall.confidence, 80);
+            assert!(wall.valid);
+
+            let three = filtered([mm(500), mm(120), mm(505)]);
+            assert_eq!(three.range, mm(503));//rounded
+            assert_eq!(three.confidence, 66);
+            assert!(three.valid);
+        }
+
+        #[test]
+        fn even_length_takes_lower_median() {
+            let f = filtered([mm(2100), mm(1000), mm(2000), mm(1020)]);
+            assert_eq!(f.range, mm(1010));//1000 and 1020 agree with 1020
+            assert_eq!(f.confidence, 50);
+        }
+
+        #[test]
+        fn exactly_half_is_not_valid() {
+            assert!(!filtered([mm(300), mm(302), mm(900), mm(950)]).valid);
+            assert!(filtered([mm(300), mm(302), mm(301), mm(950)]).valid);
+        }
+
+        #[test]
+        fn tolerance_grows_with_distance() {
+            assert!(!filtered([mm(100), mm(131), mm(69)]).valid);//3 cm close in
+            assert!(filtered([mm(2000), mm(2190), mm(1810)]).valid);//10% further out
+        }
+
+        #[test]
+        fn missing_echoes_win_a_majority() {
+            let missed = filtered(WALL_MISSED);
+            assert_eq!(missed.range, NoEcho);
+            assert_eq!(missed.confidence, 66);
+            assert!(missed.valid);
+
+            let open = filtered(OPEN_FIELD);
+            assert_eq!(open.range, OutOfRange);
+            assert!(open.valid);
+        }
+
+        #[test]
+        fn kinds_only_agree_with_themselves() {
+            let mixed = filtered([OutOfRange, NoEcho, mm(500)]);
+            assert_eq!(mixed.range, OutOfRange);
+            assert_eq!(mixed.confidence, 33);
+            assert!(!mixed.valid);
+        }
+
+        #[test]
+        fn nothing_to_filter() {
+            assert_eq!(filter(&mut []), None);
+
+            let mut ranging: Ranging<0> = Ranging::new();
+            assert_eq!(ranging.push(mm(500)), None);
+        }
+
+        #[test]
+        fn push_restarts_after_n() {
+            let mut ranging: Ranging<3> = Ranging::new();
+
+            assert_eq!(ranging.push(mm(500)), None);
+            assert_eq!(ranging.push(mm(502)), None);
+            assert_eq!(ranging.push(mm(504)).map(|f| f.range), Some(mm(502)));
+
+            //the last set is forgotten
+            assert_eq!(ranging.push(NoEcho), None);
+            assert_eq!(ranging.push(NoEcho), None);
+            assert_eq!(ranging.push(mm(504)).map(|f| f.range), Some(NoEcho));
+        }
+
+        #[test]
+        fn clear_drops_partial_set() {
+            let mut ranging: Ranging<3> = Ranging::new();
+
+            ranging.push(NoEcho);
+            ranging.push(NoEcho);
+            ranging.clear();
+            assert_eq!(ranging.push(mm(700)), None);
+            assert_eq!(ranging.push(mm(700)), None);
+            assert_eq!(ranging.push(mm(700)).map(|f| f.confidence), Some(100));
+        }
+    }
+}
+
+pub mod scan {
+    //!servo sweeps, the sensor is pointed at a series of angles and ranged at each
+    use crate::distance::{Distance, Range, MAX_RANGE};
+    use heapless::Vec;
+    use rover_protocol::UltrasonicPosition;
+
+    ///most angles a sweep can visit, every 10 degrees
+    pub const MAX_POINTS: usize = 19;
+
+    ///servo angle and the distance seen there. 0 right, 90 ahead, 180 left
+    pub type PolarMap = Vec<(u16, Distance), MAX_POINTS>;
+
+    ///angles visited by a sweep, from..=to in steps, degrees
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Sweep {
+        from: u16,
+        to: u16,
+        step: u16,
+    }
+
+    impl Default for Sweep {
+        fn default() -> Self {
+            Self::DEFAULT
+        }
+    }
+
+    impl Sweep {
+        ///right to left every 30 degrees
+        pub const DEFAULT: Sweep = Sweep { from: 0, to: 180, step: 30 };
+
+        ///None unless 0 <= from <= to <= 180, step > 0 and there are at most MAX_POINTS angles
+        pub fn new(from: u16, to: u16, step: u16) -> Option<Self> {
+            if step == 0 || from > to || to > 180 || usize::from((to - from) / step) + 1 > MAX_POINTS {
+                return None;
+            }
+            Some(Sweep { from, to, step })
+        }
+
+        pub fn angles(&self) -> impl Iterator<Item = u16> {
+            (self.from..=self.to).step_by(usize::from(self.step))
+        }
+
+        pub fn from(&self) -> u16 {
+            self.from
+        }
+
+        pub fn to(&self) -> u16 {
+            self.to
+        }
+
+        pub fn step(&self) -> u16 {
+            self.step
+        }
+    }
+
+    ///map entry for a range taken at angle
+    ///out of range reads as MAX_RANGE, no echo as zero so that direction counts as blocked
+    pub fn point(angle: u16, range: Range) -> (u16, Distance) {
+        let d = match range {
+            Range::Valid(d) => d,
+            Range::OutOfRange => MAX_RANGE,
+            Range::NoEcho => Distance::ZERO,
+        };
+        (angle, d)
+    }
+
+    ///nearest of the three fixed sensor positions, for reporting
+    pub fn position(angle: u16) -> UltrasonicPosition {
+        match angle {
+            0..=59 => UltrasonicPosition::Right,
+            60..=120 => UltrasonicPosition::Middle,
+            _ => UltrasonicPosition::Left,
+        }
+    }
+}
+
+pub mod servo {
+    //!hobby servo pulse widths and timing
+
+    ///the mount keeps ringing for a moment after the servo stops, ms
+    pub const SETTLE_MS: u32 = 40;
+    ///time between the steps of a slew limited move, ms
+    pub const STEP_MS: u32 = 20;
+
+    ///pulse widths the servo needs for 0, 90 and 180 degrees, us
+    ///min and max may be swapped for a servo mounted the other way round
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ServoCalibration {
+        min_us: u16,
+        centre_us: u16,
+        max_us: u16,
+    }
+
+    impl Default for ServoCalibration {
+        fn default() -> Self {
+            Self::SG90
+        }
+    }
+
+    impl ServoCalibration {
+        ///nominal sg90, 0.5 to 2.5 ms
+        pub const SG90: ServoCalibration = ServoCalibration { min_us: 500, centre_us: 1500, max_us: 2500 };
+        ///shortest and longest pulse accepted, us
+        pub const LIMITS_US: (u16, u16) = (300, 3000);
+
+        ///None unless every pulse is within LIMITS_US and centre lies strictly between min and max
+        pub fn new(min_us: u16, centre_us: u16, max_us: u16) -> Option<Self> {
+            let (lo, hi) = Self::LIMITS_US;
+            let in_limits = [min_us, centre_us, max_us].iter().all(|us| (lo..=hi).contains(us));
+            let ordered = (min_us < centre_us && centre_us < max_us) || (min_us > centre_us && centre_us > max_us);
+
+            (in_limits && ordered).then_some(ServoCalibration { min_us, centre_us, max_us })
+        }
+
+        pub fn min_us(&self) -> u16 {
+            self.min_us
+        }
+
+        pub fn centre_us(&self) -> u16 {
+            self.centre_us
+        }
+
+        pub fn max_us(&self) -> u16 {
+            self.max_us
+        }
+
+        ///pulse for an angle, straight lines either side of the centre. Angles past 180 are clamped
+        pub fn pulse_us(&self, angle: u16) -> u16 {
+            let angle = i32::from(angle.min(180));
+            let (from, to, offset) = if angle <= 90 {
+                (i32::from(self.min_us), i32::from(self.centre_us), angle)
+            } else {
+                (i32::from(self.centre_us), i32::from(self.max_us), angle - 90)
+            };
+
+            (from + (to - from) * offset / 90) as u16
+        }
+
+        ///angle for a pulse, the nearest degree. Pulses past min or max are clamped
+        pub fn angle(&self, pulse_us: u16) -> u16 {
+            let (min, centre, max) = (i32::from(self.min_us), i32::from(self.centre_us), i32::from(self.max_us));
+            let us = i32::from(pulse_us).clamp(min.min(max), min.max(max));
+            let (from, to, base) = if (us - min) * (centre - us) >= 0 { (min, centre, 0) } else { (centre, max, 90) };
+            let (num, den) = (90 * (us - from), to - from);
+
+            (base + (2 * num + den) / (2 * den)) as u16//rounded, num and den share a sign
+        }
+    }
+
+    ///how fast the servo turns, deg/s
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SlewRate {
+        deg_per_s: u16,
+    }
+
+    impl Default for SlewRate {
+        fn default() -> Self {
+            Self::SG90
+        }
+    }
+
+    impl SlewRate {
+        ///sg90 at 4.8 V, 0.1 s per 60 degrees
+        pub const SG90: SlewRate = SlewRate { deg_per_s: 600 };
+        pub const MAX_DEG_PER_S: u16 = 2000;
+
+        ///None outside 1 to MAX_DEG_PER_S
+        pub fn new(deg_per_s: u16) -> Option<Self> {
+            (1..=Self::MAX_DEG_PER_S).contains(&deg_per_s).then_some(SlewRate { deg_per_s })
+        }
+
+        pub fn deg_per_s(&self) -> u16 {
+            self.deg_per_s
+        }
+
+        ///time to swing between two angles, ms
+        pub fn move_ms(&self, from: u16, to: u16) -> u32 {
+            (u32::from(from.abs_diff(to)) * 1000).div_ceil(u32::from(self.deg_per_s))
+        }
+
+        ///time to swing between two angles and settle, ms. 0 when already there
+        pub fn travel_ms(&self, from: u16, to: u16) -> u32 {
+            if from == to {
+                return 0;
+            }
+            self.move_ms(from, to) + SETTLE_MS
+        }
+    }
+
+    ///where the servo was sent and when it gets there. Times are uptime, ms
+    ///the servo does not report its position, so arrival is worked out from the slew rate
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Travel {
+        angle: u16,
+        in_position_ms: u32,
+        settled: bool,//false after a step, the mount rings once the last one is done
+    }
+
+    impl Travel {
+        ///sent to angle, in position at in_position_ms
+        pub const fn new(angle: u16, in_position_ms: u32) -> Self {
+            Travel { angle, in_position_ms, settled: true }
+        }
+
+        ///angle last sent to, the servo may still be on its way there
+        pub fn angle(&self) -> u16 {
+            self.angle
+        }
+
+        ///sent to angle, in position once it has got there and settled
+        pub fn move_to(&mut self, angle: u16, now_ms: u32, rate: SlewRate) {
+            let settle_ms = if angle != self.angle || !self.settled { SETTLE_MS } else { 0 };
+
+            self.in_position_ms = self.start_ms(now_ms).wrapping_add(rate.move_ms(self.angle, angle) + settle_ms);
+            self.angle = angle;
+            self.settled = true;
+        }
+
+        ///one step of a slew limited move, done as soon as the servo gets there
+        ///settling waits for the move_to that ends the move
+        pub fn step_to(&mut self, angle: u16, now_ms: u32, rate: SlewRate) {
+            self.in_position_ms = self.start_ms(now_ms).wrapping_add(rate.move_ms(self.angle, angle));
+            self.angle = angle;
+            self.settled = false;
+        }
+
+        ///the servo may be anywhere, allow it a full swing
+        pub fn lost(&mut self, now_ms: u32, rate: SlewRate) {
+            self.in_position_ms = self.start_ms(now_ms).wrapping_add(rate.travel_ms(0, 180));
+            self.settled = true;
+        }
+
+        ///time until the servo has reached angle and settled, ms
+        pub fn remaining_ms(&self, now_ms: u32) -> u32 {
+            let left = self.in_position_ms.wrapping_sub(now_ms);
+            if (left as i32) > 0 { left } else { 0 }//wraps after 49 days of uptime
+        }
+
+        pub fn in_position(&self, now_ms: u32) -> bool {
+            self.remaining_ms(now_ms) == 0
+        }
+
+        ///a new move starts now, or worst case once the servo has got where it was going
+        fn start_ms(&self, now_ms: u32) -> u32 {
+            if self.in_position(now_ms) { now_ms } else { self.in_position_ms }
+        }
+    }
+
+    ///angles to command STEP_MS apart so the servo turns no faster than rate, the last one is to
+    pub fn slewed(from: u16, to: u16, rate: SlewRate) -> Slewed {
+        Slewed { from, to, at: from, rate, step: 0 }
+    }
+
+    pub struct Slewed {
+        from: u16,
+        to: u16,
+        at: u16,
+        rate: SlewRate,
+        step: u32,
+    }
+
+    impl Iterator for Slewed {
+        type Item = u16;
+
+        fn next(&mut self) -> Option<u16> {
+            if self.at == self.to {
+                return None;
+            }
+
+            self.step += 1;
+            let moved = (u32::from(self.rate.deg_per_s) * self.step * STEP_MS / 1000).min(u32::from(self.from.abs_diff(self.to))) as u16;
+            self.at = if self.to > self.from { self.from + moved } else { self.from - moved };
+            Some(self.at)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        ///a slew limited move as the firmware runs it, returns when it is estimated in position
+        fn slew(travel: &mut Travel, to: u16, limit: SlewRate, rate: SlewRate) -> u32 {
+            let mut now_ms = 0;
+            for step in slewed(travel.angle(), to, limit) {
+                travel.step_to(step, now_ms, rate);
+                now_ms += STEP_MS;
+            }
+            travel.move_to(to, now_ms, rate);
+            now_ms + travel.remaining_ms(now_ms)
+        }
+
+        #[test]
+        fn pulse_from_calibration() {
+            let sg90 = ServoCalibration::SG90;
+            assert_eq!([0, 45, 90, 135, 180, 500].map(|a| sg90.pulse_us(a)), [500, 1000, 1500, 2000, 2500, 2500]);
+            assert_eq!(sg90.pulse_us(1), 511);//1us resolution
+
+            let reversed = ServoCalibration::new(2400, 1450, 600).unwrap();
+            assert_eq!([0, 45, 90, 180].map(|a| reversed.pulse_us(a)), [2400, 1925, 1450, 600]);
+        }
+
+        #[test]
+        fn angle_from_pulse() {
+            let sg90 = ServoCalibration::SG90;
+            let reversed = ServoCalibration::new(2400, 1450, 600).unwrap();
+            for cal in [sg90, reversed] {
+                assert!((0..=180).all(|a| cal.angle(cal.pulse_us(a)) == a));
+            }
+            assert_eq!([0, 505, 1500, 1506, 3000].map(|us| sg90.angle(us)), [0, 0, 90, 91, 180]);
+            assert_eq!([3000, 1925, 300].map(|us| reversed.angle(us)), [0, 45, 180]);
+        }
+
+        #[test]
+        fn calibration_limits() {
+            assert_eq!(ServoCalibration::new(500, 2600, 2500), None);//centre outside
+            assert_eq!(ServoCalibration::new(200, 1500, 2500), None);
+            assert_eq!(ServoCalibration::new(500, 1500, 3001), None);
+        }
+
+        #[test]
+        fn travel_time() {
+            let rate = SlewRate::SG90;
+            assert_eq!(rate.travel_ms(0, 180), 300 + SETTLE_MS);
+            assert_eq!(rate.travel_ms(90, 60), 50 + SETTLE_MS);
+            assert_eq!(rate.travel_ms(90, 90), 0);
+            assert_eq!(SlewRate::new(0), None);
+        }
+
+        #[test]
+        fn slewed_angles() {
+            let angles: heapless::Vec<u16, 8> = slewed(90, 100, SlewRate::new(100).unwrap()).collect();
+            assert_eq!(angles[..], [92, 94, 96, 98, 100]);
+
+            assert_eq!(slewed(10, 0, SlewRate::new(30).unwrap()).count(), 17);
+            assert_eq!(slewed(10, 0, SlewRate::new(30).unwrap()).last(), Some(0));
+            assert_eq!(slewed(5, 5, SlewRate::SG90).count(), 0);
+        }
+
+        #[test]
+        fn move_settles_once() {
+            let mut travel = Travel::new(0, 0);
+
+            travel.move_to(180, 1000, SlewRate::SG90);
+            assert_eq!(travel.remaining_ms(1000), 300 + SETTLE_MS);
+
+            travel.move_to(180, 1100, SlewRate::SG90);
+            assert_eq!(travel.remaining_ms(1100), 200 + SETTLE_MS);//already on its way
+        }
+
+        #[test]
+        fn moves_queue_behind_each_other() {
+            let mut travel = Travel::new(90, 0);
+
+            travel.move_to(0, 0, SlewRate::SG90);
+            travel.move_to(180, 10, SlewRate::SG90);
+            assert_eq!(travel.remaining_ms(10), 150 + SETTLE_MS - 10 + 300 + SETTLE_MS);
+        }
+
+        #[test]
+        fn slewed_estimate_matches_the_move() {
+            let rate = SlewRate::SG90;
+
+            //at the servo's own rate a full swing takes as long as a plain move
+            assert_eq!(slew(&mut Travel::new(0, 0), 180, rate, rate), 300 + SETTLE_MS);
+
+            //slower, 2 s for 90 degrees then it settles
+            let in_position_ms = slew(&mut Travel::new(90, 0), 0, SlewRate::new(45).unwrap(), rate);
+            assert_eq!(in_position_ms, 2000 + SETTLE_MS);
+
+            //faster than the servo can go, the servo falls behind
+            let in_position_ms = slew(&mut Travel::new(0, 0), 180, SlewRate::new(1800).unwrap(), rate);
+            assert_eq!(in_position_ms, 300 + SETTLE_MS);
+        }
+
+        #[test]
+        fn lost_allows_a_full_swing() {
+            let mut travel = Travel::new(45, 0);
+
+            travel.lost(500, SlewRate::SG90);
+            assert_eq!(travel.remaining_ms(500), SlewRate::SG90.travel_ms(0, 180));
+            assert_eq!(travel.angle(), 45);
+        }
+
+        #[test]
+        fn uptime_wraps() {
+            let mut travel = Travel::new(0, u32::MAX - 20);
+
+            travel.move_to(60, u32::MAX - 10, SlewRate::SG90);
+            assert_eq!(travel.remaining_ms(u32::MAX - 10), 100 + SETTLE_MS);
+            assert_eq!(travel.remaining_ms(50), 100 + SETTLE_MS - 61);
+            assert!(travel.in_position(200));
+        }
+    }
+}
+
+pub mod avoidance {
+    //!auto mode: drive forward until something is within the stop distance,
+    //!then sweep the sensor and turn towards the clearest direction
+    //!without an echo the rover stops until it can see again
+    use crate::distance::{Distance, Range};
+    use rover_protocol::{Command, UltrasonicPosition::{self, Right, Left, Middle}, TURN_MS};
+
+    ///wait after turning before the next reading, ms
+    pub const TURN_PAUSE_MS: u16 = 500;
+    ///servo angle looking straight ahead
+    pub const AHEAD: u16 = 90;
+
+    ///servo angle for a sensor position. 0 right, 90 middle, 180 left
+    pub fn angle(position: UltrasonicPosition) -> u16 {
+        match position {
+            Right => 0,
+            Middle => AHEAD,
+            Left => 180,
+        }
+    }
+
+    ///what to do after a reading
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Decision {
+        pub drive: [Option<Command>; 2],//motor commands, in order
+        pub sweep: bool,//sweep the sensor and hand the map to `choose`
+        pub pause_ms: u16,//wait before the next reading
+    }
+
+    pub struct Avoidance {
+        moving_forward: bool,
+    }
+
+    impl Default for Avoidance {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Avoidance {
+        ///start looking ahead with the motors stopped
+        pub const fn new() -> Self {
+            Avoidance { moving_forward: false }
+        }
+
+        ///act on a range measured straight ahead
+        pub fn step(&mut self, range: Range, d_stop: Distance) -> Decision {
+            let mut decision = Decision::default();
+
+            match range {
+                Range::Valid(d) if d <= d_stop => {
+                    decision.drive = [Some(Command::BRAKE), None];
+                    decision.sweep = true;
+                },
+                Range::NoEcho => {
+                    if self.moving_forward {
+                        decision.drive = [Some(Command::Stop), None];//driving blind
+                    }
+                },
+                _ => {
+                    if !self.moving_forward {
+                        decision.drive = [Some(Command::FORWARD), None];
+                    }
+                },
+            }
+
+            self.update(&decision);
+            decision
+        }
+
+        ///act on a sweep, turn towards the clearest direction
+        ///a 90 degree turn takes TURN_MS, smaller ones proportionally less
+        pub fn choose(&mut self, map: &[(u16, Distance)], d_stop: Distance) -> Decision {
+            let mut decision = Decision { pause_ms: TURN_PAUSE_MS, ..Decision::default() };
+
+            //furthest first, then nearest to straight ahead
+            let best = map.iter().max_by(|(a, da), (b, db)| da.cmp(db).then(b.abs_diff(AHEAD).cmp(&a.abs_diff(AHEAD))));
+            decision.drive = match best {
+                Some(&(angle, d)) if d > d_stop => {
+                    let duration_ms = (u32::from(TURN_MS) * u32::from(angle.abs_diff(AHEAD)) / 90) as u16;
+                    match angle {
+                        a if a < AHEAD => [Some(Command::RightTurn { duration_ms }), None],
+                        a if a > AHEAD => [Some(Command::LeftTurn { duration_ms }), None],
+                        _ => [Some(Command::FORWARD), None],//whatever was ahead has gone
+                    }
+                },
+                _ => [Some(Command::REVERSE), Some(Command::RIGHT_TURN)],//boxed in, back out
+            };
+
+            self.update(&decision);
+            decision
+        }
+
+        fn update(&mut self, decision: &Decision) {
+            if let Some(last) = decision.drive.iter().flatten().last() {
+                self.moving_forward = matches!(last, Command::Forward { .. });
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const D_STOP: Distance = Distance::from_cm(20);
+
+        fn cm(cm: u32) -> Distance {
+            Distance::from_cm(cm)
+        }
+
+        #[test]
+        fn drives_forward_once_when_clear() {
+            let mut avoid = Avoidance::new();
+
+            let first = avoid.step(Range::Valid(cm(100)), D_STOP);
+            assert_eq!(first.drive, [Some(Command::FORWARD), None]);
+            assert!(!first.sweep);
+
+            let second = avoid.step(Range::OutOfRange, D_STOP);
+            assert_eq!(second.drive, [None, None]);//already moving
+        }
+
+        #[test]
+        fn brakes_and_sweeps_at_stop_distance() {
+            let mut avoid = Avoidance::new();
+            avoid.step(Range::Valid(cm(100)), D_STOP);
+
+            assert!(!avoid.step(Range::Valid(D_STOP.saturating_add(Distance::from_mm(1))), D_STOP).sweep);
+
+            let decision = avoid.step(Range::Valid(D_STOP), D_STOP);
+            assert_eq!(decision.drive, [Some(Command::BRAKE), None]);
+            assert!(decision.sweep);
+        }
+
+        #[test]
+        fn stops_without_echo_only_when_moving() {
+            let mut avoid = Avoidance::new();
+            assert_eq!(avoid.step(Range::NoEcho, D_STOP).drive, [None, None]);
+
+            avoid.step(Range::Valid(cm(100)), D_STOP);
+            assert_eq!(avoid.step(Range::NoEcho, D_STOP).drive, [Some(Command::Stop), None]);
+            assert_eq!(avoid.step(Range::Valid(cm(100)), D_STOP).drive, [Some(Command::FORWARD), None]);
+        }
+
+        #[test]
+        fn turns_towards_furthest() {
+            let map = [(0, cm(50)), (45, cm(150)), (90, cm(10)), (135, cm(80)), (180, cm(30))];
+            let decision = Avoidance::new().choose(&map, D_STOP);
+
+            assert_eq!(decision.drive, [Some(Command::RightTurn { duration_ms: TURN_MS / 2 }), None]);
+            assert_eq!(decision.pause_ms, TURN_PAUSE_MS);
+            assert!(!decision.sweep);
+        }
+
+        #[test]
+        fn tie_goes_to_nearest_ahead() {
+            let map = [(0, cm(200)), (60, cm(200)), (90, cm(10)), (150, cm(200)), (180, cm(200))];
+            let decision = Avoidance::new().choose(&map, D_STOP);
+
+            assert_eq!(decision.drive, [Some(Command::RightTurn { duration_ms: TURN_MS * 30 / 90 }), None]);
+
+            let map = [(60, cm(200)), (120, cm(200))];
+            assert!(matches!(Avoidance::new().choose(&map, D_STOP).drive[0], Some(Command::LeftTurn { .. })));//equally far off, the last one wins
+        }
+
+        #[test]
+        fn clear_ahead_drives_on() {
+            let map = [(0, cm(50)), (90, cm(300)), (180, cm(50))];
+            let mut avoid = Avoidance::new();
+
+            assert_eq!(avoid.choose(&map, D_STOP).drive, [Some(Command::FORWARD), None]);
+            assert_eq!(avoid.step(Range::Valid(cm(300)), D_STOP).drive, [None, None]);//counts as moving
+        }
+
+        #[test]
+        fn boxed_in_backs_out() {
+            let map = [(0, cm(20)), (90, cm(5)), (180, Distance::ZERO)];
+            let mut avoid = Avoidance::new();
+
+            let decision = avoid.choose(&map, D_STOP);
+            assert_eq!(decision.drive, [Some(Command::REVERSE), Some(Command::RIGHT_TURN)]);
+            assert_eq!(avoid.step(Range::Valid(cm(100)), D_STOP).drive, [Some(Command::FORWARD), None]);
+        }
+
+        #[test]
+        fn empty_sweep_backs_out() {
+            assert_eq!(Avoidance::new().choose(&[], D_STOP).drive, [Some(Command::REVERSE), Some(Command::RIGHT_TURN)]);
+        }
+    }
+}
+
+pub mod requests {
+    //!what each received request does to the rover
+    use rover_protocol::{Command, Reply, Request};
+
+    ///apply a request to the settings it controls, returns the reply to send
+    pub fn apply(
+        request: Request,
+        auto: &mut bool,
+        command: &mut Option<Command>,
+        telemetry_ms: &mut u16,
+        link_timeout_ms: &mut u16,
+    ) -> Reply {
+        match request {
+            Request::Auto => {
+                if *auto {
+                    *auto = false;
+                    *command = Some(Command::BRAKE);//turning from auto, brake to stop
+                } else {
+                    *auto = true;//change to auto
+                }
+                Reply::Accepted
+            },
+            Request::Drive(c) => {
+                if *auto {
+                    Reply::RejectedAuto//take no command if in auto mode
+                } else {
+                    *command = Some(c);//set command according to received frame
+                    Reply::Accepted
+                }
+            },
+            Request::TelemetryRate(ms) => {
+                *telemetry_ms = ms;
+                Reply::Accepted
+            },
+            Request::Heartbeat => Reply::Accepted,
+            Request::LinkTimeout(ms) => {
+                *link_timeout_ms = ms;
+                Reply::Accepted
+            },
+        }
+    }
+
+    ///manual mode failsafe: stop if the link has been silent too long
+    ///a pending command means a frame has just arrived
+    pub fn link_lost(silent_ms: u32, link_timeout_ms: u16, pending: bool, active: &Command) -> bool {
+        link_timeout_ms > 0 && silent_ms > u32::from(link_timeout_ms) && !pending && *active != Command::Stop
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        struct Settings {
+            auto: bool,
+            command: Option<Command>,
+            telemetry_ms: u16,
+            link_timeout_ms: u16,
+        }
+
+        impl Settings {
+            fn new(auto: bool) -> Self {
+                Settings { auto, command: None, telemetry_ms: 500, link_timeout_ms: 1000 }
+            }
+
+            fn apply(&mut self, request: Request) -> Reply {
+                apply(request, &mut self.auto, &mut self.command, &mut self.telemetry_ms, &mut self.link_timeout_ms)
+            }
+        }
+
+        #[test]
+        fn auto_toggles_and_brakes_leaving() {
+            let mut settings = Settings::new(false);
+
+            assert_eq!(settings.apply(Request::Auto), Reply::Accepted);
+            assert!(settings.auto);
+            assert_eq!(settings.command, None);
+
+            assert_eq!(settings.apply(Request::Auto), Reply::Accepted);
+            assert!(!settings.auto);
+            assert_eq!(settings.command, Some(Command::BRAKE));
+        }
+
+        #[test]
+        fn drive_only_in_manual() {
+            let mut settings = Settings::new(true);
+            assert_eq!(settings.apply(Request::Drive(Command::FORWARD)), Reply::RejectedAuto);
+            assert_eq!(settings.command, None);
+
+            let mut settings = Settings::new(false);
+            assert_eq!(settings.apply(Request::Drive(Command::FORWARD)), Reply::Accepted);
+            assert_eq!(settings.command, Some(Command::FORWARD));
+        }
+
+        #[test]
+        fn settings_apply_in_either_mode() {
+            let mut settings = Settings::new(true);
+
+            assert_eq!(settings.apply(Request::TelemetryRate(0)), Reply::Accepted);
+            assert_eq!(settings.apply(Request::LinkTimeout(250)), Reply::Accepted);
+            assert_eq!(settings.apply(Request::Heartbeat), Reply::Accepted);
+            assert_eq!((settings.telemetry_ms, settings.link_timeout_ms), (0, 250));
+            assert!(settings.auto);
+            assert_eq!(settings.command, None);
+        }
+
+        #[test]
+        fn link_lost_after_timeout() {
+            assert!(!link_lost(1000, 1000, false, &Command::FORWARD));//not yet
+            assert!(link_lost(1001, 1000, false, &Command::FORWARD));
+        }
+
+        #[test]
+        fn link_kept_when_stopped_pending_or_off() {
+            assert!(!link_lost(5000, 1000, false, &Command::Stop));
+            assert!(!link_lost(5000, 1000, true, &Command::FORWARD));
+            assert!(!link_lost(5000, 0, false, &Command::FORWARD));
+        }
+    }
+}
+
+pub mod motors {
+    //!motor patterns for each command, driven through whatever implements `MotorDriver`
+    use embedded_hal::delay::DelayNs;
+    use rover_protocol::{SPIN_MS, Command::{self, Forward, Reverse, RightTurn, LeftTurn, Brake, Stop, Donut}};
+
+    ///data for shift register
+    pub type Data = [u8; 8];
+
+    const CLOCK_MS: u32 = 5;//shift register clock half period
+
+    ///the three lines of the 74hc595 feeding the motor driver
+    pub trait MotorOutputs {
+        fn latch(&mut self, high: bool);
+        fn data(&mut self, high: bool);
+        fn clock(&mut self, high: bool);
+    }
+
+    ///what drive_motors needs from the hardware
+    pub trait MotorDriver {
+        ///put the pattern on the motors
+        fn write(&mut self, data: Data);
+        ///keep the motors as they are for ms
+        fn hold_ms(&mut self, ms: u32);
+    }
+
+    ///motors behind a shift register, timed by delay
+    pub struct ShiftRegister<O, D> {
+        outputs: O,
+        delay: D,
+    }
+
+    impl<O: MotorOutputs, D: DelayNs> ShiftRegister<O, D> {
+        pub fn new(mut outputs: O, delay: D) -> Self {
+            outputs.latch(false);
+            outputs.clock(false);
+            outputs.data(false);
+
+            ShiftRegister { outputs, delay }
+        }
+    }
+
+    impl<O: MotorOutputs, D: DelayNs> MotorDriver for ShiftRegister<O, D> {
+        fn write(&mut self, data: Data) {
+            self.outputs.latch(false);
+
+            //Send data to SER
+            for byte in data.into_iter().rev() {
+                self.outputs.data(byte != 1);//motor driver inputs are active low
+
+                self.outputs.clock(true);
+                self.delay.delay_ms(CLOCK_MS);
+                self.outputs.clock(false);
+            }
+
+            self.outputs.latch(true);
+        }
+
+        fn hold_ms(&mut self, ms: u32) {
+            self.delay.delay_ms(ms);
+        }
+    }
+
+    ///longest a timed move runs, ms. Longer ones are cut short
+    pub const MAX_RUN_MS: u32 = 10_000;
+
+    const OFF: Data = [0; 8];
+
+    ///motor pattern for a command
+    pub fn pattern(command: &Command) -> Data {
+        //[0,   1,   2,    3,    4,   5,   6,   7]
+        //[BR2, BR1, FR2, FR1, BL2, BL1, FR2, FR1]
+
+        match *command {
+            Forward { .. } => [1, 0, 1, 0, 1, 0, 1, 0],
+            Reverse { .. } => [0, 1, 0, 1, 0, 1, 0, 1],
+            RightTurn { .. } => [0, 1, 0, 1, 1, 0, 1, 0],
+            LeftTurn { .. } => [1, 0, 1, 0, 0, 1, 0, 1],
+            Brake { .. } => [0, 1, 0, 1, 0, 1, 0, 1],//hard reverse
+            Stop => OFF,
+            Donut { .. } => [0, 1, 0, 1, 1, 0, 1, 0],
+        }
+    }
+
+    ///how long the motors run before they are stopped, ms, at most MAX_RUN_MS
+    ///None if they are left as they are until the next command
+    pub fn run_ms(command: &Command) -> Option<u32> {
+        let ms = match *command {
+            Forward { duration_ms: 0, .. } | Reverse { duration_ms: 0, .. } | Stop => return None,
+            Forward { duration_ms, .. } | Reverse { duration_ms, .. } => u32::from(duration_ms),
+            RightTurn { duration_ms } | LeftTurn { duration_ms } | Brake { duration_ms } => u32::from(duration_ms),
+            Donut { spins } => u32::from(spins) * u32::from(SPIN_MS),
+        };
+        Some(ms.min(MAX_RUN_MS))
+    }
+
+    ///put the command on the motors without waiting for it
+    ///returns how long to leave them running before stop_motors, as run_ms
+    pub fn start_motors<M: MotorDriver>(motors: &mut M, command: &Command) -> Option<u32> {
+        motors.write(pattern(command));
+        run_ms(command)
+    }
+
+    pub fn stop_motors<M: MotorDriver>(motors: &mut M) {
+        motors.write(OFF);//reset shift register
+    }
+
+    ///run the command to the end, holding the motors for its whole duration
+    pub fn drive_motors<M: MotorDriver>(motors: &mut M, command: &Command) {
+        if let Some(ms) = start_motors(motors, command) {
+            if ms > 0 {
+                motors.hold_ms(ms);//run for the requested time
+            }
+            stop_motors(motors);
+        }
+    }
+
+    pub mod mock {
+        use super::{Data, MotorDriver, MotorOutputs};
+        use embedded_hal::delay::DelayNs;
+        use heapless::Vec;
+
+        pub const EVENTS: usize = 32;
+        ///enough line changes for two patterns
+        pub const OUTPUT_EVENTS: usize = 64;
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum MotorEvent {
+            Write(Data),//pattern shifted out
+            Hold(u32),//motors left running, ms
+        }
+
+        ///motor driver that records what it is asked to do instead of driving anything
+        ///events past EVENTS are dropped and flagged
+        #[derive(Debug, Default)]
+        pub struct RecordingMotors {
+            pub events: Vec<MotorEvent, EVENTS>,
+            pub overflowed: bool,
+        }
+
+        impl RecordingMotors {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            ///total time held, ms
+            pub fn held_ms(&self) -> u32 {
+                self.events.iter().map(|e| match e {
+                    MotorEvent::Hold(ms) => *ms,
+                    MotorEvent::Write(_) => 0,
+                }).sum()
+            }
+
+            fn record(&mut self, event: MotorEvent) {
+                if self.events.push(event).is_err() {
+                    self.overflowed = true;
+                }
+            }
+        }
+
+        impl MotorDriver for RecordingMotors {
+            fn write(&mut self, data: Data) {
+                self.record(MotorEvent::Write(data));
+            }
+
+            fn hold_ms(&mut self, ms: u32) {
+                self.record(MotorEvent::Hold(ms));
+            }
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum OutputEvent {
+            Latch(bool),
+            Data(bool),
+            Clock(bool),
+        }
+
+        ///shift register lines that record every change instead of driving pins
+        ///events past OUTPUT_EVENTS are dropped and flagged
+        #[derive(Debug, Default)]
+        pub struct RecordingOutputs {
+            pub events: Vec<OutputEvent, OUTPUT_EVENTS>,
+            pub overflowed: bool,
+        }
+
+        impl RecordingOutputs {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            ///level of the data line at each rising clock edge, first shifted first
+            pub fn shifted(&self) -> Vec<bool, OUTPUT_EVENTS> {
+                let mut data = false;
+                let mut bits = Vec::new();
+
+                for event in &self.events {
+                    match *event {
+                        OutputEvent::Data(high) => data = high,
+                        OutputEvent::Clock(true) => {
+                            bits.push(data).ok();//never more edges than events
+                        },
+                        _ => {},
+                    }
+                }
+                bits
+            }
+
+            fn record(&mut self, event: OutputEvent) {
+                if self.events.push(event).is_err() {
+                    self.overflowed = true;
+                }
+            }
+        }
+
+        impl MotorOutputs for RecordingOutputs {
+            fn latch(&mut self, high: bool) {
+                self.record(OutputEvent::Latch(high));
+            }
+
+            fn data(&mut self, high: bool) {
+                self.record(OutputEvent::Data(high));
+            }
+
+            fn clock(&mut self, high: bool) {
+                self.record(OutputEvent::Clock(high));
+            }
+        }
+
+        ///delay that returns at once and adds up the time it was asked for
+        #[derive(Debug, Default)]
+        pub struct RecordingDelay {
+            pub total_ns: u64,
+        }
+
+        impl RecordingDelay {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            pub fn total_ms(&self) -> u64 {
+                self.total_ns / 1_000_000
+            }
+        }
+
+        impl DelayNs for RecordingDelay {
+            fn delay_ns(&mut self, ns: u32) {
+                self.total_ns += u64::from(ns);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::mock::{MotorEvent::{self, Hold, Write}, OutputEvent, RecordingDelay, RecordingMotors, RecordingOutputs};
+        use super::*;
+        use rover_protocol::{BRAKE_MS, TURN_MS};
+
+        const FORWARD: Data = [1, 0, 1, 0, 1, 0, 1, 0];
+        const REVERSE: Data = [0, 1, 0, 1, 0, 1, 0, 1];
+        const RIGHT: Data = [0, 1, 0, 1, 1, 0, 1, 0];
+        const LEFT: Data = [1, 0, 1, 0, 0, 1, 0, 1];
+        const OFF: Data = [0; 8];
+
+        fn events(command: Command) -> RecordingMotors {
+            let mut motors = RecordingMotors::new();
+            drive_motors(&mut motors, &command);
+            assert!(!motors.overflowed);
+            motors
+        }
+
+        fn assert_events(command: Command, expected: &[MotorEvent]) {
+            assert_eq!(events(command).events[..], *expected, "{:?}", command);
+        }
+
+        #[test]
+        fn continuous_commands_keep_running() {
+            assert_events(Command::FORWARD, &[Write(FORWARD)]);
+            assert_events(Command::REVERSE, &[Write(REVERSE)]);
+        }
+
+        #[test]
+        fn timed_commands_stop_after_duration() {
+            assert_events(Command::Forward { speed: 60, duration_ms: 300 }, &[Write(FORWARD), Hold(300), Write(OFF)]);
+            assert_events(Command::Reverse { speed: 60, duration_ms: 400 }, &[Write(REVERSE), Hold(400), Write(OFF)]);
+            assert_events(Command::RIGHT_TURN, &[Write(RIGHT), Hold(u32::from(TURN_MS)), Write(OFF)]);
+            assert_events(Command::LEFT_TURN, &[Write(LEFT), Hold(u32::from(TURN_MS)), Write(OFF)]);
+            assert_events(Command::BRAKE, &[Write(REVERSE), Hold(u32::from(BRAKE_MS)), Write(OFF)]);//hard reverse
+        }
+
+        #[test]
+        fn stop_clears_the_motors() {
+            assert_events(Command::Stop, &[Write(OFF)]);
+        }
+
+        #[test]
+        fn donut_spins_in_place() {
+            assert_events(Command::Donut { spins: 3 }, &[Write(RIGHT), Hold(3 * u32::from(SPIN_MS)), Write(OFF)]);
+            assert_events(Command::Donut { spins: 0 }, &[Write(RIGHT), Write(OFF)]);
+            assert_eq!(events(Command::Donut { spins: 2 }).held_ms(), 2 * u32::from(SPIN_MS));
+        }
+
+        #[test]
+        fn long_moves_are_cut_short() {
+            assert_eq!(run_ms(&Command::Forward { speed: 60, duration_ms: u16::MAX }), Some(MAX_RUN_MS));
+            assert_eq!(run_ms(&Command::Donut { spins: u8::MAX }), Some(MAX_RUN_MS));
+            assert_eq!(events(Command::LeftTurn { duration_ms: 20_000 }).held_ms(), MAX_RUN_MS);
+            assert_eq!(run_ms(&Command::Donut { spins: 5 }), Some(5 * u32::from(SPIN_MS)));//just fits
+        }
+
+        #[test]
+        fn start_leaves_the_motors_running() {
+            let mut motors = RecordingMotors::new();
+
+            assert_eq!(start_motors(&mut motors, &Command::BRAKE), Some(u32::from(BRAKE_MS)));
+            assert_eq!(start_motors(&mut motors, &Command::FORWARD), None);
+            assert_eq!(start_motors(&mut motors, &Command::Stop), None);
+            assert_eq!(motors.events[..], [Write(REVERSE), Write(FORWARD), Write(OFF)]);//the caller times the move
+        }
+
+        #[test]
+        fn shift_register_starts_low() {
+            let register = ShiftRegister::new(RecordingOutputs::new(), RecordingDelay::new());
+            assert_eq!(register.outputs.events[..], [OutputEvent::Latch(false), OutputEvent::Clock(false), OutputEvent::Data(false)]);
+        }
+
+        #[test]
+        fn shift_register_sends_last_byte_first_inverted() {
+            let mut register = ShiftRegister::new(RecordingOutputs::new(), RecordingDelay::new());
+            register.outputs.events.clear();
+
+            register.write([1, 0, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(register.outputs.shifted()[..], [true, true, true, true, true, true, true, false]);
+
+            register.outputs.events.clear();
+            register.write(FORWARD);
+            assert_eq!(register.outputs.shifted()[..], [true, false, true, false, true, false, true, false]);
+        }
+
+        #[test]
+        fn shift_register_latches_after_shifting() {
+            let mut register = ShiftRegister::new(RecordingOutputs::new(), RecordingDelay::new());
+            register.outputs.events.clear();
+            register.write(RIGHT);
+
+            let events = &register.outputs.events;
+            assert!(!register.outputs.overflowed);
+            assert_eq!(events.first(), Some(&OutputEvent::Latch(false)));
+            assert_eq!(events.last(), Some(&OutputEvent::Latch(true)));
+            assert_eq!(events.iter().filter(|e| **e == OutputEvent::Clock(true)).count(), 8);
+            assert_eq!(events.iter().filter(|e| **e == OutputEvent::Clock(false)).count(), 8);//clock left low
+        }
+
+        #[test]
+        fn shift_register_timing() {
+            let mut register = ShiftRegister::new(RecordingOutputs::new(), RecordingDelay::new());
+
+            register.write(FORWARD);
+            assert_eq!(register.delay.total_ms(), 8 * u64::from(CLOCK_MS));//one clock per bit
+
+            drive_motors(&mut register, &Command::RIGHT_TURN);
+            assert_eq!(register.delay.total_ms(), 3 * 8 * u64::from(CLOCK_MS) + u64::from(TURN_MS));
+        }
+    }
+}
+
+pub mod shell {
+    //!line oriented text console on usart1
+    use crate::distance::Distance;
+    use crate::scan::Sweep;
+    use crate::servo::{ServoCalibration, SlewRate};
+    use core::str::FromStr;
+    use heapless::String;
+
+    ///longest line the console accepts
+    pub const LINE_LEN: usize = 32;
+
+    pub const HELP: &str = "status | set dstop <cm> | set timeout <ms> | set temp <C> | set humidity <%> | set sweep <from> <to> <step> | set servo <min us> <centre us> <max us> | set slew <deg/s> | speed <0-100> | servo <0-180> [deg/s] | scan | help";
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ShellCommand {
+        Status,
+        SetDStop(Distance),//typed in cm
+        SetLinkTimeout(u16),//link-loss timeout, ms. 0 disables
+        SetTemperature(i8),//air temperature, C
+        SetHumidity(u8),//relative humidity, %
+        SetSweep(Sweep),//angles scanned by auto mode and scan
+        Speed(u8),//motor speed limit, %
+        Servo(u16, Option<SlewRate>),//servo angle, degrees. 0 right, 90 middle, 180 left. Optionally no faster than a rate
+        SetServoCalibration(ServoCalibration),
+        SetSlew(SlewRate),
+        Scan,
+        Help,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ShellError {
+        Unknown,
+        BadArgument,
+        TooLong,
+    }
+
+    pub fn parse(line: &str) -> Result<ShellCommand, ShellError> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or(ShellError::Unknown)?;
+
+        let command = match (command, words.next()) {
+            ("status", None) => ShellCommand::Status,
+            ("set", Some("dstop")) => ShellCommand::SetDStop(Distance::from_cm(number(words.next())?)),
+            ("set", Some("timeout")) => ShellCommand::SetLinkTimeout(number(words.next()).and_then(millis)?),
+            ("set", Some("temp")) => ShellCommand::SetTemperature(number(words.next()).and_then(celsius)?),
+            ("set", Some("humidity")) => ShellCommand::SetHumidity(number(words.next()).and_then(percent)?),
+            ("set", Some("servo")) => {
+                let (min, centre, max) = (number(words.next())?, number(words.next())?, number(words.next())?);
+                ShellCommand::SetServoCalibration(ServoCalibration::new(min, centre, max).ok_or(ShellError::BadArgument)?)
+            },
+            ("set", Some("sweep")) => {
+                let (from, to, step) = (number(words.next())?, number(words.next())?, number(words.next())?);
+                ShellCommand::SetSweep(Sweep::new(from, to, step).ok_or(ShellError::BadArgument)?)
+            },
+            ("speed", arg) => ShellCommand::Speed(number(arg).and_then(percent)?),
+            ("set", Some("slew")) => ShellCommand::SetSlew(number(words.next()).and_then(slew)?),
+            ("servo", arg) => {
+                let target = number(arg).and_then(angle)?;
+                let rate = match words.next() {
+                    Some(word) => Some(number(Some(word)).and_then(slew)?),
+                    None => None,
+                };
+                ShellCommand::Servo(target, rate)
+            },
+            ("scan", None) => ShellCommand::Scan,
+            ("help", None) => ShellCommand::Help,
+            _ => return Err(ShellError::Unknown),
+        };
+
+        if words.next().is_some() {
+            return Err(ShellError::BadArgument);//trailing words
+        }
+
+        Ok(command)
+    }
+
+    fn number<T: FromStr>(word: Option<&str>) -> Result<T, ShellError> {
+        word.ok_or(ShellError::BadArgument)?
+            .parse()
+            .map_err(|_| ShellError::BadArgument)
+    }
+
+    fn millis(value: u32) -> Result<u16, ShellError> {
+        u16::try_from(value).map_err(|_| ShellError::BadArgument)
+    }
+
+    fn percent(value: u32) -> Result<u8, ShellError> {
+        if value <= 100 { Ok(value as u8) } else { Err(ShellError::BadArgument) }
+    }
+
+    fn celsius(value: i32) -> Result<i8, ShellError> {
+        if (-40..=85).contains(&value) { Ok(value as i8) } else { Err(ShellError::BadArgument) }
+    }
+
+    fn angle(value: u32) -> Result<u16, ShellError> {
+        if value <= 180 { Ok(value as u16) } else { Err(ShellError::BadArgument) }
+    }
+
+    fn slew(value: u16) -> Result<SlewRate, ShellError> {
+        SlewRate::new(value).ok_or(ShellError::BadArgument)
+    }
+
+    ///collects typed characters into lines
+    pub struct LineBuffer {
+        line: String<LINE_LEN>,
+        overflow: bool,
+    }
+
+    impl Default for LineBuffer {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl LineBuffer {
+        pub const fn new() -> Self {
+            LineBuffer { line: String::new(), overflow: false }
+        }
+
+        ///feed one received byte. Returns the parsed line once enter is seen
+        pub fn push(&mut self, byte: u8) -> Option<Result<ShellCommand, ShellError>> {
+            match byte {
+                b'\r' | b'\n' => {
+                    if self.line.is_empty() && !self.overflow {
+                        return None;//blank line or second half of \r\n
+                    }
+
+                    let result = if self.overflow { Err(ShellError::TooLong) } else { parse(&self.line) };
+                    self.line.clear();
+                    self.overflow = false;
+
+                    Some(result)
+                },
+                0x08 | 0x7F => {
+                    self.line.pop();//backspace
+                    None
+                },
+                0x20..=0x7E => {
+                    if self.line.push(byte as char).is_err() {
+                        self.overflow = true;
+                    }
+                    None
+                },
+                _ => None,//ignore other control characters
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn feed(buffer: &mut LineBuffer, bytes: &[u8]) -> Option<Result<ShellCommand, ShellError>> {
+            bytes.iter().fold(None, |last, &byte| buffer.push(byte).or(last))
+        }
+
+        #[test]
+        fn parses_commands() {
+            assert_eq!(parse("status"), Ok(ShellCommand::Status));
+            assert_eq!(parse("  set   dstop 30 "), Ok(ShellCommand::SetDStop(Distance::from_cm(30))));
+            assert_eq!(parse("set timeout 0"), Ok(ShellCommand::SetLinkTimeout(0)));
+            assert_eq!(parse("set temp -40"), Ok(ShellCommand::SetTemperature(-40)));
+            assert_eq!(parse("speed 100"), Ok(ShellCommand::Speed(100)));
+            assert_eq!(parse("servo 180"), Ok(ShellCommand::Servo(180, None)));
+            assert_eq!(parse("set sweep 30 150 60"), Ok(ShellCommand::SetSweep(Sweep::new(30, 150, 60).unwrap())));
+        }
+
+        #[test]
+        fn rejects_bad_arguments() {
+            assert_eq!(parse("speed 101"), Err(ShellError::BadArgument));
+            assert_eq!(parse("speed fast"), Err(ShellError::BadArgument));
+            assert_eq!(parse("speed"), Err(ShellError::BadArgument));
+            assert_eq!(parse("set temp 86"), Err(ShellError::BadArgument));
+            assert_eq!(parse("set timeout 65536"), Err(ShellError::BadArgument));
+            assert_eq!(parse("servo 181"), Err(ShellError::BadArgument));
+            assert_eq!(parse("set sweep 90 0 10"), Err(ShellError::BadArgument));
+        }
+
+        #[test]
+        fn rejects_trailing_words() {
+            assert_eq!(parse("status now"), Err(ShellError::Unknown));
+            assert_eq!(parse("speed 50 60"), Err(ShellError::BadArgument));
+            assert_eq!(parse("set dstop 30 cm"), Err(ShellError::BadArgument));
+        }
+
+        #[test]
+        fn rejects_unknown() {
+            assert_eq!(parse(""), Err(ShellError::Unknown));
+            assert_eq!(parse("fly"), Err(ShellError::Unknown));
+            assert_eq!(parse("set colour 3"), Err(ShellError::Unknown));
+        }
+
+        #[test]
+        fn line_ends_on_cr_lf_or_both() {
+            let mut buffer = LineBuffer::new();
+
+            assert_eq!(feed(&mut buffer, b"status\r"), Some(Ok(ShellCommand::Status)));
+            assert_eq!(feed(&mut buffer, b"help\n"), Some(Ok(ShellCommand::Help)));
+            assert_eq!(buffer.push(b'\r'), None);//blank line
+            assert_eq!(feed(&mut buffer, b"scan\r"), Some(Ok(ShellCommand::Scan)));
+            assert_eq!(buffer.push(b'\n'), None);//second half of \r\n
+        }
+
+        #[test]
+        fn backspace_and_control_characters() {
+            let mut buffer = LineBuffer::new();
+            assert_eq!(feed(&mut buffer, b"statx\x08us\x1b\r"), Some(Ok(ShellCommand::Status)));
+            assert_eq!(feed(&mut buffer, b"helpp\x7f\n"), Some(Ok(ShellCommand::Help)));
+        }
+
+        #[test]
+        fn overflow_is_too_long() {
+            let mut buffer = LineBuffer::new();
+
+            assert_eq!(feed(&mut buffer, &[b'a'; LINE_LEN + 1]), None);
+            assert_eq!(buffer.push(b'\r'), Some(Err(ShellError::TooLong)));
+            assert_eq!(feed(&mut buffer, b"status\r"), Some(Ok(ShellCommand::Status)));//starts over
+
+            assert_eq!(feed(&mut buffer, &[b' '; LINE_LEN]), None);
+            assert_eq!(feed(&mut buffer, b"\r"), Some(Err(ShellError::Unknown)));//exactly full still parses
+        }
+    }
+}
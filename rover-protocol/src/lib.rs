@@ -0,0 +1,686 @@
+//!rover commands and the framed protocol carrying them over usart1
+//!shared by the firmware and host tools
+//!frame: [START, SEQ, LEN, ID, PAYLOAD(LEN bytes), CRC]
+//!crc is crc-8 (poly 0x07, init 0x00) over SEQ, LEN, ID and PAYLOAD
+//!every command frame is answered with a reply frame carrying the same SEQ
+
+#![no_std]
+
+///motor duty for full speed, %
+pub const FULL_SPEED: u8 = 100;
+///default turn duration, ms
+pub const TURN_MS: u16 = 250;
+///default brake duration, ms
+pub const BRAKE_MS: u16 = 200;
+///duration of a single donut spin, ms
+pub const SPIN_MS: u16 = 2000;
+
+///motion commands. A `duration_ms` of 0 on Forward/Reverse runs until the next command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Forward { speed: u8, duration_ms: u16 },
+    Reverse { speed: u8, duration_ms: u16 },
+    RightTurn { duration_ms: u16 },
+    LeftTurn { duration_ms: u16 },
+    Brake { duration_ms: u16 },
+    Stop,
+    Donut { spins: u8 },
+}
+
+impl Command {
+    pub const FORWARD: Command = Command::Forward { speed: FULL_SPEED, duration_ms: 0 };
+    pub const REVERSE: Command = Command::Reverse { speed: FULL_SPEED, duration_ms: 0 };
+    pub const RIGHT_TURN: Command = Command::RightTurn { duration_ms: TURN_MS };
+    pub const LEFT_TURN: Command = Command::LeftTurn { duration_ms: TURN_MS };
+    pub const BRAKE: Command = Command::Brake { duration_ms: BRAKE_MS };
+    pub const DONUT: Command = Command::Donut { spins: 1 };
+
+    ///true if the motors keep running once the command has been driven
+    pub fn is_continuous(&self) -> bool {
+        matches!(*self, Command::Forward { duration_ms: 0, .. } | Command::Reverse { duration_ms: 0, .. })
+    }
+
+    ///motor duty the command runs at, %
+    pub fn speed(&self) -> u8 {
+        match *self {
+            Command::Forward { speed, .. } | Command::Reverse { speed, .. } => speed,
+            _ => FULL_SPEED,
+        }
+    }
+}
+
+///where the ultrasonic sensor is pointing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UltrasonicPosition {
+    Right,
+    Left,
+    Middle
+}
+
+use Command::{Forward, Reverse, RightTurn, LeftTurn, Brake, Stop, Donut};
+
+///start of frame marker
+pub const START: u8 = 0x7E;
+///max payload bytes carried by a frame
+pub const MAX_PAYLOAD: usize = 16;
+///max encoded frame size
+pub const MAX_FRAME: usize = MAX_PAYLOAD + 5;
+
+///command ids
+pub const ID_AUTO: u8 = 0x41;
+pub const ID_FORWARD: u8 = 0x42;
+pub const ID_REVERSE: u8 = 0x43;
+pub const ID_RIGHT_TURN: u8 = 0x44;
+pub const ID_LEFT_TURN: u8 = 0x45;
+pub const ID_BRAKE: u8 = 0x46;
+pub const ID_STOP: u8 = 0x47;
+pub const ID_DONUT: u8 = 0x48;
+pub const ID_TELEMETRY_RATE: u8 = 0x49;
+pub const ID_HEARTBEAT: u8 = 0x4A;
+pub const ID_LINK_TIMEOUT: u8 = 0x4B;
+
+///frames sent by the rover
+pub const ID_TELEMETRY: u8 = 0x80;
+pub const ID_REPLY: u8 = 0x81;
+pub const ID_SCAN: u8 = 0x82;
+
+///a decoded command frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Auto,//toggle auto/manual
+    Drive(Command),
+    TelemetryRate(u16),
+    Heartbeat,//keeps the link alive, no other effect
+    LinkTimeout(u16),//stop manual motion after this many ms without a frame. 0 disables
+}
+
+impl Request {
+    ///encode the request as a command frame
+    pub fn frame(&self) -> Frame {
+        match *self {
+            Request::Auto => Frame { seq: 0, id: ID_AUTO, len: 0, payload: [0; MAX_PAYLOAD] },
+            Request::Drive(command) => command_frame(&command),
+            Request::TelemetryRate(ms) => u16_frame(ID_TELEMETRY_RATE, ms),
+            Request::Heartbeat => Frame { seq: 0, id: ID_HEARTBEAT, len: 0, payload: [0; MAX_PAYLOAD] },
+            Request::LinkTimeout(ms) => u16_frame(ID_LINK_TIMEOUT, ms),
+        }
+    }
+}
+
+///interpret a command frame
+pub fn request(frame: &Frame) -> Result<Request, Reply> {
+    match frame.id {
+        ID_AUTO if frame.payload().is_empty() => Ok(Request::Auto),
+        ID_FORWARD..=ID_DONUT => command(frame).map(Request::Drive).ok_or(Reply::Malformed),
+        ID_TELEMETRY_RATE => u16_payload(frame).map(Request::TelemetryRate).ok_or(Reply::Malformed),
+        ID_HEARTBEAT if frame.payload().is_empty() => Ok(Request::Heartbeat),
+        ID_LINK_TIMEOUT => u16_payload(frame).map(Request::LinkTimeout).ok_or(Reply::Malformed),
+        ID_AUTO | ID_HEARTBEAT => Err(Reply::Malformed),
+        _ => Err(Reply::UnknownCommand),
+    }
+}
+
+///outcome of a command frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Accepted = 0,
+    RejectedAuto = 1,//motion commands are ignored in auto mode
+    UnknownCommand = 2,
+    Malformed = 3,//bad length, payload or crc
+}
+
+impl Reply {
+    ///reply frame answering the command with sequence number seq
+    pub fn frame(self, seq: u8) -> Frame {
+        let mut payload = [0; MAX_PAYLOAD];
+        payload[0] = self as u8;
+
+        Frame { seq, id: ID_REPLY, len: 1, payload }
+    }
+
+    ///returns the reply and the sequence number it answers
+    pub fn from_frame(frame: &Frame) -> Option<(Self, u8)> {
+        let reply = match (frame.id, frame.payload()) {
+            (ID_REPLY, [0]) => Reply::Accepted,
+            (ID_REPLY, [1]) => Reply::RejectedAuto,
+            (ID_REPLY, [2]) => Reply::UnknownCommand,
+            (ID_REPLY, [3]) => Reply::Malformed,
+            _ => return None,
+        };
+
+        Some((reply, frame.seq))
+    }
+}
+
+///map a frame to its motion command
+///payloads are little endian:
+///Forward/Reverse [speed, duration_ms], turns/Brake [duration_ms], Donut [spins]
+///an empty payload selects the default manoeuvre
+pub fn command(frame: &Frame) -> Option<Command> {
+    let p = frame.payload();
+
+    match (frame.id, p.len()) {
+        (ID_FORWARD, 0) => Some(Command::FORWARD),
+        (ID_FORWARD, 3) => Some(Forward { speed: speed(p[0])?, duration_ms: u16_at(p, 1) }),
+        (ID_REVERSE, 0) => Some(Command::REVERSE),
+        (ID_REVERSE, 3) => Some(Reverse { speed: speed(p[0])?, duration_ms: u16_at(p, 1) }),
+        (ID_RIGHT_TURN, 0) => Some(Command::RIGHT_TURN),
+        (ID_RIGHT_TURN, 2) => Some(RightTurn { duration_ms: u16_at(p, 0) }),
+        (ID_LEFT_TURN, 0) => Some(Command::LEFT_TURN),
+        (ID_LEFT_TURN, 2) => Some(LeftTurn { duration_ms: u16_at(p, 0) }),
+        (ID_BRAKE, 0) => Some(Command::BRAKE),
+        (ID_BRAKE, 2) => Some(Brake { duration_ms: u16_at(p, 0) }),
+        (ID_STOP, 0) => Some(Stop),
+        (ID_DONUT, 0) => Some(Command::DONUT),
+        (ID_DONUT, 1) => Some(Donut { spins: p[0] }),
+        _ => None,
+    }
+}
+
+///encode a motion command as a frame
+pub fn command_frame(command: &Command) -> Frame {
+    let mut payload = [0; MAX_PAYLOAD];
+
+    let (id, len) = match *command {
+        Forward { speed, duration_ms } => (ID_FORWARD, put_motion(&mut payload, speed, duration_ms)),
+        Reverse { speed, duration_ms } => (ID_REVERSE, put_motion(&mut payload, speed, duration_ms)),
+        RightTurn { duration_ms } => (ID_RIGHT_TURN, put_u16(&mut payload, 0, duration_ms)),
+        LeftTurn { duration_ms } => (ID_LEFT_TURN, put_u16(&mut payload, 0, duration_ms)),
+        Brake { duration_ms } => (ID_BRAKE, put_u16(&mut payload, 0, duration_ms)),
+        Stop => (ID_STOP, 0),
+        Donut { spins } => {
+            payload[0] = spins;
+            (ID_DONUT, 1)
+        },
+    };
+
+    Frame { seq: 0, id, len: len as u8, payload }
+}
+
+fn u16_payload(frame: &Frame) -> Option<u16> {
+    match frame.payload() {
+        p if p.len() == 2 => Some(u16_at(p, 0)),
+        _ => None,
+    }
+}
+
+fn u16_frame(id: u8, value: u16) -> Frame {
+    let mut payload = [0; MAX_PAYLOAD];
+    let len = put_u16(&mut payload, 0, value);
+
+    Frame { seq: 0, id, len: len as u8, payload }
+}
+
+///rover status pushed periodically to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Telemetry {
+    pub auto: bool,
+    pub distance: Option<u16>,//cm
+    pub position: UltrasonicPosition,
+    pub command: Command,
+    pub uptime_ms: u32,
+    pub link_timeout_ms: u16,//0 => failsafe disabled
+    pub link_age_ms: u16,//time since the last valid frame, saturating
+}
+
+impl Telemetry {
+    ///payload: [auto, distance(u16, 0xFFFF none), position, command id, command payload(3), uptime_ms(u32),
+    ///link_timeout_ms(u16), link_age_ms(u16)]
+    pub fn frame(&self) -> Frame {
+        let mut payload = [0; MAX_PAYLOAD];
+        let command = command_frame(&self.command);
+
+        payload[0] = self.auto as u8;
+        put_u16(&mut payload, 1, self.distance.unwrap_or(u16::MAX));
+        payload[3] = match self.position {
+            UltrasonicPosition::Right => 0,
+            UltrasonicPosition::Middle => 1,
+            UltrasonicPosition::Left => 2,
+        };
+        payload[4] = command.id;
+        payload[5..5 + command.payload().len()].copy_from_slice(command.payload());
+        payload[8..12].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        put_u16(&mut payload, 12, self.link_timeout_ms);
+        put_u16(&mut payload, 14, self.link_age_ms);
+
+        Frame { seq: 0, id: ID_TELEMETRY, len: 16, payload }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        let p = frame.payload();
+        if frame.id != ID_TELEMETRY || p.len() != 16 {
+            return None;
+        }
+
+        let position = match p[3] {
+            0 => UltrasonicPosition::Right,
+            1 => UltrasonicPosition::Middle,
+            2 => UltrasonicPosition::Left,
+            _ => return None,
+        };
+
+        //command payload length follows from its id
+        let len = match p[4] {
+            ID_FORWARD | ID_REVERSE => 3,
+            ID_RIGHT_TURN | ID_LEFT_TURN | ID_BRAKE => 2,
+            ID_DONUT => 1,
+            _ => 0,
+        };
+        let command = command(&Frame::new(p[4], &p[5..5 + len])?)?;
+
+        let distance = match u16_at(p, 1) {
+            u16::MAX => None,
+            d => Some(d),
+        };
+
+        Some(Telemetry {
+            auto: p[0] != 0,
+            distance,
+            position,
+            command,
+            uptime_ms: u32::from_le_bytes([p[8], p[9], p[10], p[11]]),
+            link_timeout_ms: u16_at(p, 12),
+            link_age_ms: u16_at(p, 14),
+        })
+    }
+}
+
+///points carried by one scan frame
+pub const SCAN_POINTS: usize = 4;
+
+///part of a servo sweep, sent once the sweep is done
+///a sweep longer than SCAN_POINTS goes out as several frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scan {
+    pub index: u8,//position of the first point in the sweep
+    pub count: u8,//points in the whole sweep
+    len: u8,
+    points: [(u8, u16); SCAN_POINTS],//servo angle, degrees, and distance, cm
+}
+
+impl Scan {
+    ///None if there are more than SCAN_POINTS points
+    pub fn new(index: u8, count: u8, points: &[(u8, u16)]) -> Option<Self> {
+        if points.len() > SCAN_POINTS {
+            return None;
+        }
+
+        let mut scan = Scan { index, count, len: points.len() as u8, points: [(0, 0); SCAN_POINTS] };
+        scan.points[..points.len()].copy_from_slice(points);
+
+        Some(scan)
+    }
+
+    pub fn points(&self) -> &[(u8, u16)] {
+        &self.points[..self.len as usize]
+    }
+
+    ///payload: [index, count, (angle, distance(u16)) per point]
+    pub fn frame(&self) -> Frame {
+        let mut payload = [0; MAX_PAYLOAD];
+
+        payload[0] = self.index;
+        payload[1] = self.count;
+        let mut len = 2;
+        for &(angle, cm) in self.points() {
+            payload[len] = angle;
+            len = put_u16(&mut payload, len + 1, cm);
+        }
+
+        Frame { seq: 0, id: ID_SCAN, len: len as u8, payload }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        let p = frame.payload();
+        let n = p.len().saturating_sub(2) / 3;
+        if frame.id != ID_SCAN || p.len() != 2 + 3 * n || n > SCAN_POINTS {
+            return None;
+        }
+
+        let mut scan = Scan { index: p[0], count: p[1], len: 0, points: [(0, 0); SCAN_POINTS] };
+        for point in p[2..].chunks(3) {
+            scan.points[scan.len as usize] = (point[0], u16_at(point, 1));
+            scan.len += 1;
+        }
+
+        Some(scan)
+    }
+}
+
+fn speed(byte: u8) -> Option<u8> {
+    if byte <= FULL_SPEED { Some(byte) } else { None }//duty is a percentage
+}
+
+fn u16_at(p: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([p[i], p[i + 1]])
+}
+
+fn put_u16(p: &mut [u8], i: usize, value: u16) -> usize {
+    p[i..i + 2].copy_from_slice(&value.to_le_bytes());
+    i + 2
+}
+
+fn put_motion(p: &mut [u8], speed: u8, duration_ms: u16) -> usize {
+    p[0] = speed;
+    put_u16(p, 1, duration_ms)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u8,
+    pub id: u8,
+    len: u8,
+    payload: [u8; MAX_PAYLOAD],
+}
+
+impl Frame {
+    pub fn new(id: u8, payload: &[u8]) -> Option<Self> {
+        if payload.len() > MAX_PAYLOAD {
+            return None;
+        }
+
+        let mut frame = Frame { seq: 0, id, len: payload.len() as u8, payload: [0; MAX_PAYLOAD] };
+        frame.payload[..payload.len()].copy_from_slice(payload);
+
+        Some(frame)
+    }
+
+    pub fn with_seq(mut self, seq: u8) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len as usize]
+    }
+
+    ///write the frame into buf, returns number of bytes written
+    pub fn encode(&self, buf: &mut [u8; MAX_FRAME]) -> usize {
+        let len = self.len as usize;
+
+        buf[0] = START;
+        buf[1] = self.seq;
+        buf[2] = self.len;
+        buf[3] = self.id;
+        buf[4..4 + len].copy_from_slice(self.payload());
+        buf[4 + len] = crc8(&buf[1..4 + len]);
+
+        len + 5
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    Length { seq: u8 },//declared length larger than MAX_PAYLOAD
+    Crc { seq: u8 },//checksum mismatch
+}
+
+impl DecodeError {
+    ///sequence number of the rejected frame, as received
+    pub fn seq(&self) -> u8 {
+        match *self {
+            DecodeError::Length { seq } | DecodeError::Crc { seq } => seq,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Seq,
+    Length,
+    Id,
+    Payload,
+    Crc,
+}
+
+///byte at a time frame decoder
+pub struct Decoder {
+    state: State,
+    frame: Frame,
+    index: usize,
+    crc: u8,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            state: State::Start,
+            frame: Frame { seq: 0, id: 0, len: 0, payload: [0; MAX_PAYLOAD] },
+            index: 0,
+            crc: 0,
+        }
+    }
+
+    ///true when no frame is partially received
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Start
+    }
+
+    ///discard any partially received frame
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+    }
+
+    ///feed one received byte. Returns a result once a whole frame has been seen
+    pub fn feed(&mut self, byte: u8) -> Option<Result<Frame, DecodeError>> {
+        match self.state {
+            State::Start => {
+                if byte == START {
+                    self.crc = 0;
+                    self.state = State::Seq;
+                }//anything outside a frame is noise
+            },
+            State::Seq => {
+                self.frame.seq = byte;
+                self.crc = crc8_update(self.crc, byte);
+                self.state = State::Length;
+            },
+            State::Length => {
+                if byte as usize > MAX_PAYLOAD {
+                    self.state = State::Start;
+                    return Some(Err(DecodeError::Length { seq: self.frame.seq }));
+                }
+                self.frame.len = byte;
+                self.crc = crc8_update(self.crc, byte);
+                self.state = State::Id;
+            },
+            State::Id => {
+                self.frame.id = byte;
+                self.crc = crc8_update(self.crc, byte);
+                self.index = 0;
+                self.state = if self.frame.len == 0 { State::Crc } else { State::Payload };
+            },
+            State::Payload => {
+                self.frame.payload[self.index] = byte;
+                self.crc = crc8_update(self.crc, byte);
+                self.index += 1;
+                if self.index == self.frame.len as usize {
+                    self.state = State::Crc;
+                }
+            },
+            State::Crc => {
+                self.state = State::Start;
+                if byte == self.crc {
+                    return Some(Ok(self.frame));
+                } else {
+                    return Some(Err(DecodeError::Crc { seq: self.frame.seq }));
+                }
+            },
+        }
+
+        None
+    }
+}
+
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| crc8_update(crc, *byte))
+}
+
+fn crc8_update(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+    for _ in 0..8 {
+        if crc & 0x80 != 0 {
+            crc = (crc << 1) ^ 0x07;
+        } else {
+            crc <<= 1;
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(frame: &Frame) -> ([u8; MAX_FRAME], usize) {
+        let mut buf = [0; MAX_FRAME];
+        let len = frame.encode(&mut buf);
+        (buf, len)
+    }
+
+    ///feed bytes, return the last result
+    fn feed(decoder: &mut Decoder, bytes: &[u8]) -> Option<Result<Frame, DecodeError>> {
+        bytes.iter().fold(None, |last, &byte| decoder.feed(byte).or(last))
+    }
+
+    fn assert_same(a: &Frame, b: &Frame) {
+        assert_eq!((a.seq, a.id, a.payload()), (b.seq, b.id, b.payload()));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut decoder = Decoder::new();
+
+        for frame in [
+            Frame::new(ID_FORWARD, &[]).unwrap().with_seq(7),
+            command_frame(&Forward { speed: 60, duration_ms: 1500 }).with_seq(8),
+            Frame::new(ID_STOP, &[0xAB; MAX_PAYLOAD]).unwrap().with_seq(255),
+        ] {
+            let (buf, len) = encoded(&frame);
+            assert_eq!(len, frame.payload().len() + 5);
+
+            let (last, rest) = buf[..len].split_last().unwrap();
+            assert_eq!(feed(&mut decoder, rest), None);//nothing until the crc
+            assert!(!decoder.is_idle());
+
+            let decoded = decoder.feed(*last).unwrap().unwrap();
+            assert_same(&decoded, &frame);
+            assert!(decoder.is_idle());
+        }
+    }
+
+    #[test]
+    fn auto_bytes() {
+        let (buf, len) = encoded(&Frame::new(ID_AUTO, &[]).unwrap());
+        assert_eq!(buf[..len], [0x7E, 0x00, 0x00, 0x41, 0xC0]);//as given in the readme
+    }
+
+    #[test]
+    fn payload_too_long() {
+        assert_eq!(Frame::new(ID_FORWARD, &[0; MAX_PAYLOAD + 1]), None);
+    }
+
+    #[test]
+    fn bad_crc_rejected() {
+        let mut decoder = Decoder::new();
+        let (mut buf, len) = encoded(&Frame::new(ID_BRAKE, &[]).unwrap().with_seq(3));
+        buf[len - 1] ^= 0x01;
+
+        assert_eq!(feed(&mut decoder, &buf[..len]), Some(Err(DecodeError::Crc { seq: 3 })));
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn corrupt_payload_rejected() {
+        let mut decoder = Decoder::new();
+        let (mut buf, len) = encoded(&command_frame(&Command::Donut { spins: 2 }).with_seq(4));
+        buf[4] ^= 0x80;
+
+        assert_eq!(feed(&mut decoder, &buf[..len]), Some(Err(DecodeError::Crc { seq: 4 })));
+    }
+
+    #[test]
+    fn oversized_length_rejected() {
+        let mut decoder = Decoder::new();
+
+        assert_eq!(decoder.feed(START), None);
+        assert_eq!(decoder.feed(9), None);
+        assert_eq!(decoder.feed(MAX_PAYLOAD as u8 + 1), Some(Err(DecodeError::Length { seq: 9 })));
+        assert!(decoder.is_idle());//looks for the next START straight away
+
+        let frame = Frame::new(ID_AUTO, &[]).unwrap();
+        let (buf, len) = encoded(&frame);
+        assert_same(&feed(&mut decoder, &buf[..len]).unwrap().unwrap(), &frame);
+    }
+
+    #[test]
+    fn noise_before_start_ignored() {
+        let mut decoder = Decoder::new();
+        assert_eq!(feed(&mut decoder, b"status\r\n\x00\xFF"), None);
+        assert!(decoder.is_idle());
+
+        let frame = command_frame(&Command::BRAKE).with_seq(1);
+        let (buf, len) = encoded(&frame);
+        assert_same(&feed(&mut decoder, &buf[..len]).unwrap().unwrap(), &frame);
+    }
+
+    #[test]
+    fn reset_drops_partial_frame() {
+        let mut decoder = Decoder::new();
+        let frame = command_frame(&Command::LEFT_TURN).with_seq(2);
+        let (buf, len) = encoded(&frame);
+
+        feed(&mut decoder, &buf[..3]);//link dropped mid frame
+        decoder.reset();
+        assert!(decoder.is_idle());
+
+        assert_same(&feed(&mut decoder, &buf[..len]).unwrap().unwrap(), &frame);
+    }
+
+    #[test]
+    fn truncated_frame_swallows_the_next_start() {
+        let mut decoder = Decoder::new();
+        let frame = command_frame(&Command::RIGHT_TURN);
+        let (buf, len) = encoded(&frame);
+
+        feed(&mut decoder, &buf[..2]);
+        assert!(!matches!(feed(&mut decoder, &buf[..len]), Some(Ok(_))));//why a stalled frame has to be reset
+    }
+
+    #[test]
+    fn commands_survive_the_frame() {
+        for c in [
+            Forward { speed: 60, duration_ms: 1500 },
+            Reverse { speed: 0, duration_ms: 0 },
+            RightTurn { duration_ms: 400 },
+            LeftTurn { duration_ms: u16::MAX },
+            Brake { duration_ms: 100 },
+            Stop,
+            Donut { spins: 3 },
+        ] {
+            assert_eq!(command(&command_frame(&c)), Some(c));
+        }
+    }
+
+    #[test]
+    fn empty_payload_is_the_default() {
+        assert_eq!(command(&Frame::new(ID_FORWARD, &[]).unwrap()), Some(Command::FORWARD));
+        assert_eq!(command(&Frame::new(ID_RIGHT_TURN, &[]).unwrap()), Some(Command::RIGHT_TURN));
+        assert_eq!(command(&Frame::new(ID_DONUT, &[]).unwrap()), Some(Command::DONUT));
+    }
+
+    #[test]
+    fn bad_payload_rejected() {
+        assert_eq!(command(&Frame::new(ID_FORWARD, &[101, 0, 0]).unwrap()), None);//over full speed
+        assert_eq!(command(&Frame::new(ID_FORWARD, &[50]).unwrap()), None);
+        assert_eq!(command(&Frame::new(ID_AUTO, &[]).unwrap()), None);//not a motion command
+        assert_eq!(command(&Frame::new(0x4F, &[]).unwrap()), None);
+    }
+}
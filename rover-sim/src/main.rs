@@ -0,0 +1,169 @@
+//!host simulator for auto mode
+//!runs the firmware's avoidance logic on a modelled rover in a 2d world of polygon obstacles
+
+mod output;
+mod sim;
+mod world;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use rover_core::distance::Distance;
+use rover_core::scan::Sweep;
+use rover_core::servo::SlewRate;
+
+use sim::{Params, Sim};
+use world::World;
+
+#[derive(Parser)]
+#[command(about = "Simulate the obstacle avoiding rover in auto mode")]
+struct Cli {
+    ///obstacles, one polygon per line as `x,y` vertices in cm. Built-in arena if not given
+    #[arg(short, long)]
+    world: Option<PathBuf>,
+
+    ///start position and heading, `x,y,degrees`
+    #[arg(long, default_value = "30,30,0", value_parser = start)]
+    start: (f64, f64, f64),
+
+    #[arg(short = 't', long, default_value_t = 60)]
+    seconds: u32,
+
+    ///stop distance, cm
+    #[arg(long, default_value_t = 20)]
+    d_stop: u32,
+
+    ///speed at full duty, cm/s
+    #[arg(long, default_value_t = 50.)]
+    speed: f64,
+
+    ///turning rate on the spot, deg/s
+    #[arg(long, default_value_t = 360.)]
+    turn_rate: f64,
+
+    ///rover radius, cm
+    #[arg(long, default_value_t = 10.)]
+    radius: f64,
+
+    ///air temperature, C
+    #[arg(long, default_value_t = 20., allow_negative_numbers = true)]
+    air_temp: f64,
+
+    ///give the rover the air temperature, otherwise it assumes 20 C
+    #[arg(long)]
+    compensate: bool,
+
+    ///servo angles swept when blocked, `from,to,step` degrees
+    #[arg(long, default_value = "0,180,30", value_parser = sweep)]
+    sweep: Sweep,
+
+    ///servo slew rate, deg/s
+    #[arg(long, default_value = "600", value_parser = servo_rate)]
+    servo_rate: SlewRate,
+
+    ///write the trajectory as csv here, stdout if neither --csv nor --svg is given
+    #[arg(long)]
+    csv: Option<PathBuf>,
+
+    ///draw the world and trajectory here
+    #[arg(long)]
+    svg: Option<PathBuf>,
+}
+
+fn start(arg: &str) -> Result<(f64, f64, f64), String> {
+    let v = arg
+        .split(',')
+        .map(|s| s.trim().parse::<f64>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match v[..] {
+        [x, y, heading] => Ok((x, y, heading)),
+        _ => Err("expected x,y,degrees".into()),
+    }
+}
+
+fn sweep(arg: &str) -> Result<Sweep, String> {
+    let v = arg
+        .split(',')
+        .map(|s| s.trim().parse::<u16>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match v[..] {
+        [from, to, step] => Sweep::new(from, to, step).ok_or_else(|| "expected 0 <= from <= to <= 180, step > 0, at most 19 angles".into()),
+        _ => Err("expected from,to,step".into()),
+    }
+}
+
+fn servo_rate(arg: &str) -> Result<SlewRate, String> {
+    let rate = arg.trim().parse::<u16>().map_err(|e| e.to_string())?;
+    SlewRate::new(rate).ok_or_else(|| format!("expected 1 to {} deg/s", SlewRate::MAX_DEG_PER_S))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let world = match &cli.world {
+        Some(path) => match fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| World::parse(&text)) {
+            Ok(world) => world,
+            Err(e) => {
+                eprintln!("cannot load {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => World::demo(),
+    };
+
+    let (x, y, heading) = cli.start;
+    if world.collides((x, y), cli.radius) {
+        eprintln!("start position is inside an obstacle");
+        return ExitCode::FAILURE;
+    }
+
+    let params = Params {
+        speed_cm_s: cli.speed,
+        turn_deg_s: cli.turn_rate,
+        radius_cm: cli.radius,
+        d_stop: Distance::from_cm(cli.d_stop),
+        step_ms: 10,
+        air_c: cli.air_temp,
+        compensate: cli.compensate,
+        sweep: cli.sweep,
+        servo_rate: cli.servo_rate,
+    };
+    let mut sim = Sim::new(&world, params, (x, y), heading);
+    sim.run(cli.seconds * 1000);
+
+    if let Err(e) = write(&cli, &world, &sim) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    eprintln!(
+        "{:.1} s, {:.0} cm travelled, {} readings, {}",
+        f64::from(sim.t_ms()) / 1000.,
+        sim.travelled(),
+        sim.readings.len(),
+        match sim.collision {
+            Some((x, y)) => format!("collided at {:.0},{:.0}", x, y),
+            None => "no collision".into(),
+        }
+    );
+
+    if sim.collision.is_some() { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+fn write(cli: &Cli, world: &World, sim: &Sim) -> io::Result<()> {
+    match &cli.csv {
+        Some(path) => output::csv(&mut BufWriter::new(File::create(path)?), &sim.samples, &sim.readings)?,
+        None if cli.svg.is_none() => output::csv(&mut io::stdout().lock(), &sim.samples, &sim.readings)?,
+        None => {},
+    }
+
+    if let Some(path) = &cli.svg {
+        output::svg(&mut BufWriter::new(File::create(path)?), world, &sim.samples, &sim.readings, sim.collision)?;
+    }
+    Ok(())
+}
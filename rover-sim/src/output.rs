@@ -0,0 +1,78 @@
+//!trajectory as csv and svg
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use rover_core::distance::Range;
+
+use crate::sim::{Reading, Sample};
+use crate::world::{Point, World};
+
+///svg margin around the world, cm
+const MARGIN: f64 = 10.;
+
+///one row per step, distance filled in on the steps a reading was taken
+///`out_of_range` when nothing was within sensor range
+pub fn csv(out: &mut impl Write, samples: &[Sample], readings: &[Reading]) -> io::Result<()> {
+    writeln!(out, "t_ms,x_cm,y_cm,heading_deg,motion,servo_deg,distance_mm")?;
+
+    let mut readings = readings.iter().peekable();
+    for s in samples {
+        let distance = match readings.peek() {
+            Some(r) if r.t_ms == s.t_ms => readings.next().map(|r| match r.range {
+                Range::Valid(d) => d.mm().to_string(),
+                Range::OutOfRange => "out_of_range".into(),
+                Range::NoEcho => "no_echo".into(),
+            }),
+            _ => None,
+        };
+        writeln!(
+            out,
+            "{},{:.1},{:.1},{:.1},{},{},{}",
+            s.t_ms, s.pos.0, s.pos.1, s.heading_deg, s.motion, s.servo_deg, distance.unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+///obstacles in grey, the path in blue, sensor beams in orange, start green, end or collision red
+pub fn svg(out: &mut impl Write, world: &World, samples: &[Sample], readings: &[Reading], collision: Option<Point>) -> io::Result<()> {
+    let ((x0, y0), (x1, y1)) = world.bounds();
+    let (w, h) = (x1 - x0 + 2. * MARGIN, y1 - y0 + 2. * MARGIN);
+
+    let mut body = String::new();
+    for polygon in &world.obstacles {
+        let _ = writeln!(body, r##"<polygon points="{}" fill="#ccc" fill-opacity="0.5" stroke="#444"/>"##, points(polygon));
+    }
+    for r in readings {
+        if let Some(hit) = r.hit {
+            let _ = writeln!(
+                body,
+                r##"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="orange" stroke-width="0.5"/>"##,
+                r.from.0, r.from.1, hit.0, hit.1
+            );
+        }
+    }
+    let path: Vec<Point> = samples.iter().map(|s| s.pos).collect();
+    let _ = writeln!(body, r#"<polyline points="{}" fill="none" stroke="blue"/>"#, points(&path));
+    if let (Some(first), Some(last)) = (samples.first(), samples.last()) {
+        let _ = writeln!(body, r#"<circle cx="{:.1}" cy="{:.1}" r="3" fill="green"/>"#, first.pos.0, first.pos.1);
+        let end = collision.unwrap_or(last.pos);
+        let _ = writeln!(body, r#"<circle cx="{:.1}" cy="{:.1}" r="3" fill="red"/>"#, end.0, end.1);
+    }
+
+    //flip y so +y points up as in the world
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w:.1} {h:.1}" width="{:.0}" height="{:.0}">"#,
+        w * 3.,
+        h * 3.
+    )?;
+    writeln!(out, r#"<g transform="translate({:.1} {:.1}) scale(1 -1)">"#, MARGIN - x0, h - MARGIN + y0)?;
+    write!(out, "{}", body)?;
+    writeln!(out, "</g>\n</svg>")
+}
+
+fn points(points: &[Point]) -> String {
+    points.iter().map(|(x, y)| format!("{:.1},{:.1}", x, y)).collect::<Vec<_>>().join(" ")
+}
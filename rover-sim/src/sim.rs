@@ -0,0 +1,249 @@
+//!rover model driven by the auto mode logic the firmware runs
+
+use rover_core::avoidance::{Avoidance, AHEAD};
+use rover_core::distance::{Distance, Fixed, Range, SpeedOfSound, ECHO_TIMEOUT_US, MAX_RANGE};
+use rover_core::motors;
+use rover_core::ranging::{Ranging, SAMPLES};
+use rover_core::scan::{self, PolarMap, Sweep};
+use rover_core::servo::SlewRate;
+use rover_protocol::{Command, FULL_SPEED};
+
+use crate::world::{radians, Point, World};
+
+///time between echoes in auto mode, the trigger task holds off this long after each ping
+const MEASURE_MS: u32 = 60;
+///half width of the hc-sr04 beam, degrees
+const BEAM_DEG: f64 = 7.5;
+///rays cast across the beam
+const BEAM_RAYS: usize = 7;
+
+pub struct Params {
+    pub speed_cm_s: f64,//at full speed
+    pub turn_deg_s: f64,//turning on the spot
+    pub radius_cm: f64,//rover footprint, sensor at the front edge
+    pub d_stop: Distance,
+    pub step_ms: u32,
+    pub air_c: f64,//temperature the rover runs in
+    pub compensate: bool,//the rover knows the air temperature, else it assumes the fallback
+    pub sweep: Sweep,
+    pub servo_rate: SlewRate,
+}
+
+///rover pose and state every step
+pub struct Sample {
+    pub t_ms: u32,
+    pub pos: Point,
+    pub heading_deg: f64,
+    pub motion: &'static str,
+    pub servo_deg: u16,
+}
+
+///a sensor reading, hit is where the beam landed if anything was in range
+pub struct Reading {
+    pub t_ms: u32,
+    pub from: Point,
+    pub hit: Option<Point>,
+    pub range: Range,
+}
+
+pub struct Sim<'a> {
+    world: &'a World,
+    params: Params,
+    pos: Point,
+    heading: f64,//radians, counter clockwise from +x
+    motion: Command,//what the motors keep doing between commands
+    servo_deg: u16,
+    avoid: Avoidance,
+    ranging: Ranging<SAMPLES>,
+    air: SpeedOfSound,//what the echo really travels at
+    sound: SpeedOfSound,//what the firmware converts with
+    t_ms: u32,
+    pub samples: Vec<Sample>,
+    pub readings: Vec<Reading>,
+    pub collision: Option<Point>,
+}
+
+impl<'a> Sim<'a> {
+    pub fn new(world: &'a World, params: Params, pos: Point, heading_deg: f64) -> Self {
+        let avoid = Avoidance::new();
+        let air = SpeedOfSound::new((params.air_c * 10.).round() as i16, None);
+        let sound = if params.compensate {
+            SpeedOfSound::from_source(&mut Fixed { temperature_dc: (params.air_c * 10.).round() as i16, humidity_pct: None })
+        } else {
+            SpeedOfSound::FALLBACK
+        };
+        Sim {
+            world,
+            params,
+            pos,
+            heading: radians(heading_deg),
+            motion: Command::Stop,
+            servo_deg: AHEAD,
+            avoid,
+            ranging: Ranging::new(),
+            air,
+            sound,
+            t_ms: 0,
+            samples: Vec::new(),
+            readings: Vec::new(),
+            collision: None,
+        }
+    }
+
+    ///run the control loop until time is up or the rover hits something
+    pub fn run(&mut self, duration_ms: u32) {
+        self.record();
+
+        while self.t_ms < duration_ms && self.collision.is_none() {
+            self.advance(MEASURE_MS);
+            let d = self.measure();
+            let filtered = match self.ranging.push(d) {
+                Some(filtered) if filtered.valid => filtered,
+                _ => continue,//more echoes to take, or they disagree
+            };
+            let mut decision = self.avoid.step(filtered.range, self.params.d_stop);
+
+            for c in decision.drive.iter().flatten() {
+                self.drive(c);
+            }
+
+            if decision.sweep {
+                let map = self.sweep();
+                decision = self.avoid.choose(&map, self.params.d_stop);
+                self.servo_deg = AHEAD;//settles while turning
+
+                for c in decision.drive.iter().flatten() {
+                    self.drive(c);
+                }
+                self.ranging.clear();
+            }
+
+            self.advance(u32::from(decision.pause_ms));
+        }
+    }
+
+    ///range every angle of the sweep as the firmware does, disagreeing readings count as no echo
+    fn sweep(&mut self) -> PolarMap {
+        let mut map = PolarMap::new();
+
+        for angle in self.params.sweep.angles() {
+            let travel_ms = self.params.servo_rate.travel_ms(self.servo_deg, angle);
+            self.servo_deg = angle;
+            self.advance(travel_ms);
+
+            let mut ranging: Ranging<SAMPLES> = Ranging::new();
+            let range = loop {
+                self.advance(MEASURE_MS + 10);
+                let d = self.measure();
+                if let Some(filtered) = ranging.push(d) {
+                    break if filtered.valid { filtered.range } else { Range::NoEcho };
+                }
+            };
+            map.push(scan::point(angle, range)).ok();
+        }
+        map
+    }
+
+    ///distance as the firmware would see it, cm
+    fn measure(&mut self) -> Range {
+        let bearing = self.heading + radians(f64::from(self.servo_deg) - f64::from(AHEAD));
+        let from = (
+            self.pos.0 + self.params.radius_cm * self.heading.cos(),
+            self.pos.1 + self.params.radius_cm * self.heading.sin(),
+        );
+
+        //nearest thing anywhere in the cone answers first
+        let nearest = (0..BEAM_RAYS)
+            .map(|i| bearing + radians(BEAM_DEG * (2. * i as f64 / (BEAM_RAYS - 1) as f64 - 1.)))
+            .filter_map(|angle| self.world.raycast(from, angle, f64::from(MAX_RANGE.mm()) / 10.))
+            .min_by(f64::total_cmp);
+
+        //sound crosses the real air, the firmware converts assuming its own temperature
+        let pulse_us = nearest.map_or(ECHO_TIMEOUT_US, |cm| (cm * 20_000_000. / f64::from(self.air.mm_per_s())) as u32);
+        let range = self.sound.range(pulse_us);
+
+        self.readings.push(Reading {
+            t_ms: self.t_ms,
+            from,
+            hit: nearest.map(|cm| (from.0 + cm * bearing.cos(), from.1 + cm * bearing.sin())),
+            range,
+        });
+        range
+    }
+
+    ///the motors as drive_motors runs them
+    fn drive(&mut self, command: &Command) {
+        self.motion = *command;
+        if let Some(ms) = motors::run_ms(command) {
+            self.advance(ms);
+            self.motion = Command::Stop;//reset shift register
+        }
+    }
+
+    ///move with the current motion for ms
+    fn advance(&mut self, ms: u32) {
+        let mut left = ms;
+        while left > 0 && self.collision.is_none() {
+            let dt = left.min(self.params.step_ms);
+            left -= dt;
+            self.t_ms += dt;
+
+            let (v, w) = self.velocity();
+            let secs = f64::from(dt) / 1000.;
+            self.heading += radians(w) * secs;
+            self.pos.0 += v * secs * self.heading.cos();
+            self.pos.1 += v * secs * self.heading.sin();
+
+            if self.world.collides(self.pos, self.params.radius_cm) {
+                self.collision = Some(self.pos);
+            }
+            self.record();
+        }
+    }
+
+    ///forward cm/s and counter clockwise deg/s for the current motion
+    ///brake reverses the motors, modelled as an instant stop
+    fn velocity(&self) -> (f64, f64) {
+        let v = self.params.speed_cm_s * f64::from(self.motion.speed()) / f64::from(FULL_SPEED);
+        let w = self.params.turn_deg_s;
+
+        match self.motion {
+            Command::Forward { .. } => (v, 0.),
+            Command::Reverse { .. } => (-v, 0.),
+            Command::RightTurn { .. } | Command::Donut { .. } => (0., -w),
+            Command::LeftTurn { .. } => (0., w),
+            Command::Brake { .. } | Command::Stop => (0., 0.),
+        }
+    }
+
+    fn record(&mut self) {
+        self.samples.push(Sample {
+            t_ms: self.t_ms,
+            pos: self.pos,
+            heading_deg: self.heading.to_degrees(),
+            motion: name(&self.motion),
+            servo_deg: self.servo_deg,
+        });
+    }
+
+    ///distance covered, cm
+    pub fn travelled(&self) -> f64 {
+        self.samples.windows(2).map(|w| (w[1].pos.0 - w[0].pos.0).hypot(w[1].pos.1 - w[0].pos.1)).sum()
+    }
+
+    pub fn t_ms(&self) -> u32 {
+        self.t_ms
+    }
+}
+
+pub fn name(command: &Command) -> &'static str {
+    match command {
+        Command::Forward { .. } => "forward",
+        Command::Reverse { .. } => "reverse",
+        Command::RightTurn { .. } => "right",
+        Command::LeftTurn { .. } => "left",
+        Command::Brake { .. } => "brake",
+        Command::Stop => "stop",
+        Command::Donut { .. } => "donut",
+    }
+}
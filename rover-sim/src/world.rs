@@ -0,0 +1,120 @@
+//!2d world of polygon obstacles, cm
+
+use std::f64::consts::PI;
+
+pub type Point = (f64, f64);
+
+///obstacles are closed polygons, only their edges matter
+///so a polygon around the start position acts as the arena walls
+pub struct World {
+    pub obstacles: Vec<Vec<Point>>,
+}
+
+impl World {
+    ///300 x 200 cm walled arena with a few boxes
+    pub fn demo() -> Self {
+        World {
+            obstacles: vec![
+                vec![(0., 0.), (300., 0.), (300., 200.), (0., 200.)],
+                vec![(120., 60.), (160., 60.), (160., 100.), (120., 100.)],
+                vec![(220., 130.), (260., 130.), (260., 200.), (220., 200.)],
+                vec![(40., 140.), (70., 170.), (40., 200.), (10., 170.)],
+            ],
+        }
+    }
+
+    ///one obstacle per line as space separated `x,y` vertices, # starts a comment
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut obstacles = Vec::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let polygon = line
+                .split_whitespace()
+                .map(point)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("line {}: expected x,y pairs", n + 1))?;
+            if polygon.len() < 2 {
+                return Err(format!("line {}: need at least two vertices", n + 1));
+            }
+            obstacles.push(polygon);
+        }
+
+        if obstacles.is_empty() {
+            return Err("no obstacles".into());
+        }
+        Ok(World { obstacles })
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = (Point, Point)> + '_ {
+        self.obstacles.iter().flat_map(|polygon| {
+            polygon.iter().zip(polygon.iter().cycle().skip(1)).map(|(a, b)| (*a, *b))
+        })
+    }
+
+    ///smallest box holding every obstacle, (min, max)
+    pub fn bounds(&self) -> (Point, Point) {
+        self.obstacles.iter().flatten().fold(
+            ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN)),
+            |((x0, y0), (x1, y1)), &(x, y)| ((x0.min(x), y0.min(y)), (x1.max(x), y1.max(y))),
+        )
+    }
+
+    ///distance from origin to the nearest edge along angle, None if nothing within max
+    pub fn raycast(&self, origin: Point, angle: f64, max: f64) -> Option<f64> {
+        let dir = (angle.cos(), angle.sin());
+
+        self.edges()
+            .filter_map(|(a, b)| ray_segment(origin, dir, a, b))
+            .filter(|d| *d <= max)
+            .min_by(f64::total_cmp)
+    }
+
+    ///true if a circle of radius r around p touches any edge
+    pub fn collides(&self, p: Point, r: f64) -> bool {
+        self.edges().any(|(a, b)| segment_distance(p, a, b) < r)
+    }
+}
+
+fn point(word: &str) -> Option<Point> {
+    let (x, y) = word.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+fn cross(a: Point, b: Point) -> f64 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+///distance along the unit direction dir from origin to segment ab
+fn ray_segment(origin: Point, dir: Point, a: Point, b: Point) -> Option<f64> {
+    let edge = (b.0 - a.0, b.1 - a.1);
+    let denom = cross(dir, edge);
+    if denom.abs() < 1e-12 {
+        return None;//parallel
+    }
+
+    let to_a = (a.0 - origin.0, a.1 - origin.1);
+    let t = cross(to_a, edge) / denom;//along the ray
+    let u = cross(to_a, dir) / denom;//along the edge
+    (t >= 0. && (0. ..=1.).contains(&u)).then_some(t)
+}
+
+fn segment_distance(p: Point, a: Point, b: Point) -> f64 {
+    let edge = (b.0 - a.0, b.1 - a.1);
+    let len2 = edge.0 * edge.0 + edge.1 * edge.1;
+    let t = if len2 > 0. {
+        (((p.0 - a.0) * edge.0 + (p.1 - a.1) * edge.1) / len2).clamp(0., 1.)
+    } else {
+        0.
+    };
+    let closest = (a.0 + t * edge.0, a.1 + t * edge.1);
+    (p.0 - closest.0).hypot(p.1 - closest.1)
+}
+
+pub fn radians(degrees: f64) -> f64 {
+    degrees * PI / 180.
+}
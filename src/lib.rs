@@ -1,18 +1,44 @@
 #![no_std]
 
 pub use stm32f103_pac as pac;
-
-///data for shift register
-pub type Data = [u8; 8];
-
-pub static mut MOVING_FORWARD: bool = false;
+pub use rover_protocol as protocol;
+pub use rover_protocol::{Command, UltrasonicPosition, FULL_SPEED, TURN_MS, BRAKE_MS, SPIN_MS};
+pub use rover_core::{avoidance, distance, ranging, requests, scan, servo, shell, motors::Data};
 
 pub mod clocks {
     use stm32f103_pac::{RCC, FLASH};
 
+    const HSI: u32 = 8_000_000;//clock out of reset
+    const MAX_SYSCLK: u32 = 72_000_000;
+    const MAX_PCLK1: u32 = 36_000_000;
+
+    ///sysclk source, the pll is used whenever the target differs from the source
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Source {
+        Hsi,//internal 8MHz rc, reaches the pll halved
+        Hse(u32),//crystal, Hz
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ClockError {
+        Sysclk,//no pll setting gives the target from the source
+        Prescaler,//not a divider the bus supports
+        TooFast,//sysclk above 72MHz or pclk1 above 36MHz
+    }
+
+    ///clock tree. Defaults to hse 8MHz, sysclk 72MHz, apb1 /2, as the board runs
+    ///set up with the builder methods, then `configure`
     pub struct Clocks {
         pub rcc:  RCC,
         pub flash: FLASH,
+        source: Source,
+        target: u32,
+        hpre: u32,
+        ppre1: u32,
+        ppre2: u32,
+        hclk: u32,
+        pclk1: u32,
+        pclk2: u32,
     }
 
     impl Clocks {
@@ -20,68 +46,248 @@ pub mod clocks {
             Clocks {
                 rcc,
                 flash,
+                source: Source::Hse(8_000_000),
+                target: MAX_SYSCLK,
+                hpre: 1,
+                ppre1: 2,
+                ppre2: 1,
+                hclk: HSI,
+                pclk1: HSI,
+                pclk2: HSI,
             }
         }
 
-        pub fn configure(&mut self) {
+        pub fn hsi(mut self) -> Self {
+            self.source = Source::Hsi;
+            self
+        }
+
+        ///crystal frequency, Hz
+        pub fn hse(mut self, hz: u32) -> Self {
+            self.source = Source::Hse(hz);
+            self
+        }
+
+        ///target sysclk, Hz
+        pub fn sysclk(mut self, hz: u32) -> Self {
+            self.target = hz;
+            self
+        }
+
+        ///ahb prescaler: 1, 2, 4, 8, 16, 64, 128, 256 or 512
+        pub fn hclk_div(mut self, div: u32) -> Self {
+            self.hpre = div;
+            self
+        }
+
+        ///apb1 prescaler: 1, 2, 4, 8 or 16
+        pub fn pclk1_div(mut self, div: u32) -> Self {
+            self.ppre1 = div;
+            self
+        }
+
+        ///apb2 prescaler: 1, 2, 4, 8 or 16
+        pub fn pclk2_div(mut self, div: u32) -> Self {
+            self.ppre2 = div;
+            self
+        }
+
+        ///ahb clock, Hz
+        pub fn hclk(&self) -> u32 {
+            self.hclk
+        }
+
+        ///apb1 clock, Hz
+        pub fn pclk1(&self) -> u32 {
+            self.pclk1
+        }
+
+        ///apb2 clock, Hz
+        pub fn pclk2(&self) -> u32 {
+            self.pclk2
+        }
+
+        ///clock of tim2-4, doubled when apb1 is divided
+        pub fn timclk1(&self) -> u32 {
+            if self.hclk == self.pclk1 { self.pclk1 } else { self.pclk1 * 2 }
+        }
+
+        ///clock of tim1, doubled when apb2 is divided
+        pub fn timclk2(&self) -> u32 {
+            if self.hclk == self.pclk2 { self.pclk2 } else { self.pclk2 * 2 }
+        }
+
+        ///switch to the configured tree. Nothing is touched if it can not be reached
+        pub fn configure(&mut self) -> Result<(), ClockError> {
+            let hpre = hpre_bits(self.hpre).ok_or(ClockError::Prescaler)?;
+            let ppre1 = ppre_bits(self.ppre1).ok_or(ClockError::Prescaler)?;
+            let ppre2 = ppre_bits(self.ppre2).ok_or(ClockError::Prescaler)?;
+
+            let (source_hz, sw) = match self.source {
+                Source::Hsi => (HSI, 0b00),
+                Source::Hse(hz) => (hz, 0b01),
+            };
+            let pll = if self.target == source_hz { None } else { Some(pll(self.source, self.target)?) };
+
+            let sysclk = self.target;
+            let hclk = sysclk / self.hpre;
+            let pclk1 = hclk / self.ppre1;
+            if sysclk > MAX_SYSCLK || pclk1 > MAX_PCLK1 {
+                return Err(ClockError::TooFast);
+            }
+
+            let latency = match sysclk {
+                0..=24_000_000 => 0b000,
+                24_000_001..=48_000_000 => 0b001,
+                _ => 0b010,
+            };
             self.flash.acr.modify(|_, w| unsafe { w
-                .latency().bits(0b010) });//flash latency of two wait states
+                .latency().bits(latency) });//flash wait states for sysclk, set before speeding up
             self.flash.acr.modify(|_, w| w
                                   .prftbe().set_bit());//enable prefetch buffer
 
             self.rcc.cfgr.modify(|_, w| unsafe { w
-                .ppre1().bits(0b100)//apb1 divide by 2
-                    .ppre2().bits(0b000)//apb2 not divided
-                    .pllxtpre().clear_bit()//hse entry into pll not divided
+                .ppre1().bits(ppre1)//apb1 prescaler
+                    .ppre2().bits(ppre2)//apb2 prescaler
+                    .hpre().bits(hpre)//ahb prescaler
             });
 
-            self.rcc.cr.write(|w| w.hseon().set_bit());//turn on hse
-            while self.rcc.cr.read().hserdy().bit_is_clear() {}//wait until hse ready
-
-            self.rcc.cfgr.modify(|_, w| unsafe { w
-                .pllsrc().set_bit()//pll source set as pll
-                    .pllmul().bits(0b0111)//pll mult factor set as 9
-                    .hpre().bits(0b0000)//sysclk not divided
-            });
+            if let Source::Hse(_) = self.source {
+                self.rcc.cr.modify(|_, w| w.hseon().set_bit());//turn on hse
+                while self.rcc.cr.read().hserdy().bit_is_clear() {}//wait until hse ready
+            }
 
-            self.rcc.cr.modify(|_, w| w.pllon().set_bit());//turn pll on
-            while self.rcc.cr.read().pllrdy().bit_is_clear() {}//wait until pll ready
+            let sw = match pll {
+                Some((pllmul, halve_hse)) => {
+                    self.rcc.cfgr.modify(|_, w| unsafe { w
+                        .pllsrc().bit(sw == 0b01)//pll from hse, else hsi/2
+                            .pllxtpre().bit(halve_hse)//hse entry into pll divided
+                            .pllmul().bits(pllmul)//pll mult factor - 2
+                    });
+
+                    self.rcc.cr.modify(|_, w| w.pllon().set_bit());//turn pll on
+                    while self.rcc.cr.read().pllrdy().bit_is_clear() {}//wait until pll ready
+                    0b10
+                },
+                None => sw,
+            };
 
             self.rcc.cfgr.modify(|_, w| unsafe {
-                w.sw().bits(0b10)//pll selected as sysclk
+                w.sw().bits(sw)//select sysclk
             });
 
-            while !self.rcc.cfgr.read().sws().eq(&0b10) {}//confirm selected clock
+            while !self.rcc.cfgr.read().sws().eq(&sw) {}//confirm selected clock
+
+            self.hclk = hclk;
+            self.pclk1 = pclk1;
+            self.pclk2 = hclk / self.ppre2;
+            Ok(())
+        }
+    }
+
+    ///pllmul bits and whether hse is halved on the way in
+    fn pll(source: Source, target: u32) -> Result<(u8, bool), ClockError> {
+        let inputs: &[(u32, bool)] = match source {
+            Source::Hsi => &[(HSI / 2, false)],
+            Source::Hse(hz) => &[(hz, false), (hz / 2, true)],
+        };
+
+        inputs.iter().copied()
+            .filter(|(hz, _)| *hz > 0 && target % hz == 0)
+            .map(|(hz, halve)| (target / hz, halve))
+            .find(|(mul, _)| (2..=16).contains(mul))
+            .map(|(mul, halve)| ((mul - 2) as u8, halve))
+            .ok_or(ClockError::Sysclk)
+    }
 
-            //Clocks set so...
-            //HCLK -> 72MHz
-            //PCLK1 -> 36MHz
-            //PCLK2 -> 72MHz
+    fn hpre_bits(div: u32) -> Option<u8> {
+        match div {
+            1 => Some(0b0000),
+            2 => Some(0b1000),
+            4 => Some(0b1001),
+            8 => Some(0b1010),
+            16 => Some(0b1011),
+            64 => Some(0b1100),
+            128 => Some(0b1101),
+            256 => Some(0b1110),
+            512 => Some(0b1111),
+            _ => None,
         }
     }
+
+    fn ppre_bits(div: u32) -> Option<u8> {
+        match div {
+            1 => Some(0b000),
+            2 => Some(0b100),
+            4 => Some(0b101),
+            8 => Some(0b110),
+            16 => Some(0b111),
+            _ => None,
+        }
+    }
+
+    ///timer prescaler register value for a counter ticking at tick_hz
+    pub fn prescaler(timer_clk: u32, tick_hz: u32) -> u16 {
+        ((timer_clk / tick_hz).clamp(1, 0x1_0000) - 1) as u16
+    }
 }
 
 pub mod usart1 {
     use super::clocks::Clocks;
-    use stm32f103_pac::USART1;
+    use core::convert::Infallible;
+    use core::sync::atomic::{compiler_fence, AtomicU32, Ordering};
+    use embedded_io::{ErrorType, Read, Write};
+    use heapless::spsc::{Consumer, Producer, Queue};
+    use stm32f103_pac::{DMA1, USART1};
+
+    const MAX_BAUD_ERROR: u32 = 25;//worst accepted baud rate error, per mille
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum BaudError {
+        OutOfRange,//divider does not fit brr
+        Inaccurate { actual: u32 },//closest achievable rate too far off
+    }
+
+    ///brr value for the baud rate from a usart clock of pclk Hz
+    ///brr holds pclk / baud, mantissa in the upper 12 bits and sixteenths in the lower 4
+    pub fn brr(pclk: u32, baud: u32) -> Result<u16, BaudError> {
+        if baud == 0 {
+            return Err(BaudError::OutOfRange);
+        }
+
+        let div = (pclk + baud / 2) / baud;//rounded to the nearest sixteenth
+        if !(16..=0xFFFF).contains(&div) {
+            return Err(BaudError::OutOfRange);//mantissa must be at least 1
+        }
+
+        let actual = pclk / div;
+        if actual.abs_diff(baud) * 1000 > baud * MAX_BAUD_ERROR {
+            return Err(BaudError::Inaccurate { actual });
+        }
+        Ok(div as u16)
+    }
 
     pub struct Usart1 {
        pub usart1: USART1,
     }
 
     impl Usart1 {
-        pub fn config(clocks: &Clocks, usart1: USART1) -> Self {
+        ///usart1 at the baud rate, 8N1
+        ///fails if pclk2 can not get close enough to the rate
+        pub fn config(clocks: &Clocks, usart1: USART1, baud: u32) -> Result<Self, BaudError> {
+            let brr = brr(clocks.pclk2(), baud)?;
+
             //Enable clock to usart1
             clocks.rcc.apb2enr.modify(|_, w| w.usart1en().set_bit());
 
             usart1.cr1.reset();
             usart1.cr1.write(|w| w.ue().set_bit());//Enable usart1
-            usart1.brr.write(|w| unsafe { w.div_mantissa().bits(468).div_fraction().bits(12) });//Program baud rate to 9600
+            usart1.brr.write(|w| unsafe { w.bits(u32::from(brr)) });//Program baud rate
             
             usart1.cr1.modify(|_, w| w.te().set_bit());//Enable transmitter. WORKS ONLY WITH 'MODIFY'
             usart1.cr1.modify(|_, w| w.re().set_bit());//Enable receiver
 
-            Usart1 { usart1 }
+            Ok(Usart1 { usart1 })
         }
 
         pub fn transmit(&mut self, data: u16) {
@@ -102,52 +308,331 @@ pub mod usart1 {
         pub fn disable_interrupt(&mut self) {
             self.usart1.cr1.modify(|_, w| w.rxneie().clear_bit());//usart interrupt rxnie disable
         }
+
+        ///hand usart1 over to the interrupt driven driver backed by the two queues
+        ///returns the driver to be serviced from the usart1 interrupt, the receive end and the transmit end
+        pub fn into_buffered(
+            mut self,
+            rx: &'static mut RxQueue,
+            tx: &'static mut TxQueue,
+        ) -> (BufferedUsart1, Consumer<'static, u8, RX_LEN>, Tx) {
+            let (rx_producer, rx_consumer) = rx.split();
+            let (tx_producer, tx_consumer) = tx.split();
+
+            self.enable_interrupt();//rxne interrupt on, txe interrupt only while there is data to send
+
+            (
+                BufferedUsart1 { usart1: self.usart1, rx: rx_producer, tx: tx_consumer },
+                rx_consumer,
+                Tx { tx: tx_producer },
+            )
+        }
     }
-}
 
-pub mod led {
-    use super::clocks::Clocks;
-    use stm32f103_pac::GPIOC;
+    impl ErrorType for Usart1 {
+        type Error = Infallible;
+    }
+
+    ///blocks for the first byte then takes whatever else has already arrived
+    impl Read for Usart1 {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            buf[0] = self.receive() as u8;
+            let mut n = 1;
+            while n < buf.len() && self.usart1.sr.read().rxne().bit_is_set() {
+                buf[n] = self.usart1.dr.read().dr().bits() as u8;
+                n += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Usart1 {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            for byte in buf {
+                while self.usart1.sr.read().txe().bit_is_clear() {}//Wait until txe is set
+                self.usart1.dr.write(|w| unsafe { w.dr().bits(u16::from(*byte)) });
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            while self.usart1.sr.read().tc().bit_is_clear() {}//Wait until tc is set
+            Ok(())
+        }
+    }
+
+    ///fastest rate the link is run at, the hc-06's AT+BAUD8
+    pub const MAX_BAUD: u32 = 115_200;
+    ///how often the receive task drains the rx queue, ms
+    pub const RX_POLL_MS: u32 = 5;
+    ///four polls' worth of bytes at MAX_BAUD, 10 bits a byte, so a late poll loses nothing
+    pub const RX_LEN: usize = (4 * RX_POLL_MS * MAX_BAUD / 10 / 1000).next_power_of_two() as usize;
+    pub const TX_LEN: usize = 256;
+
+    static RX_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+    static RX_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+    ///bytes lost in the usart since boot, the interrupt was held off past the next byte
+    pub fn rx_overruns() -> u32 {
+        RX_OVERRUNS.load(Ordering::Relaxed)
+    }
+
+    ///bytes received since boot that found the rx queue full
+    pub fn rx_dropped() -> u32 {
+        RX_DROPPED.load(Ordering::Relaxed)
+    }
+
+    pub type RxQueue = Queue<u8, RX_LEN>;
+    pub type TxQueue = Queue<u8, TX_LEN>;
 
-    pub struct Led {
-        portc: GPIOC,
+    ///interrupt side of the buffered driver
+    pub struct BufferedUsart1 {
+        usart1: USART1,
+        rx: Producer<'static, u8, RX_LEN>,
+        tx: Consumer<'static, u8, TX_LEN>,
     }
 
-    impl Led {
-        pub fn new(clocks: &Clocks, portc: GPIOC) -> Self {
-            //Enable clock to gpioc
-            clocks.rcc.apb2enr.modify(|_, w| w.iopcen().set_bit());
+    impl BufferedUsart1 {
+        ///service the usart1 interrupt
+        ///moves a received byte into the rx queue and feeds the next queued byte to the transmitter
+        pub fn on_interrupt(&mut self) {
+            let sr = self.usart1.sr.read();
 
-            //Configure pin 13 as output
-            portc.crh
-                .write(|w| unsafe {
-                    w.mode13().bits(0b01)//Output mode, max speed 10 MHz.
-                        .cnf13().bits(0b00)//General purpose output push-pull
-                });
+            if sr.ore().bit_is_set() {
+                RX_OVERRUNS.fetch_add(1, Ordering::Relaxed);//the byte after the one in dr is gone
+            }
 
-            portc.odr.modify(|_, w| w.odr13().set_bit());//Put OFF initially
+            if sr.rxne().bit_is_set() || sr.ore().bit_is_set() {
+                let byte = self.usart1.dr.read().dr().bits() as u8;//reading dr after sr clears rxne and ore
+                if self.rx.enqueue(byte).is_err() {
+                    RX_DROPPED.fetch_add(1, Ordering::Relaxed);//rx queue full, byte dropped
+                }
+            }
 
-            Led { portc }
+            if sr.txe().bit_is_set() && self.usart1.cr1.read().txeie().bit_is_set() {
+                if dma_busy() {
+                    //dma owns the transmitter, carry on once it completes
+                    self.usart1.cr1.modify(|_, w| w.txeie().clear_bit());
+                    return;
+                }
+
+                match self.tx.dequeue() {
+                    Some(byte) => self.usart1.dr.write(|w| unsafe { w.dr().bits(u16::from(byte)) }),
+                    None => self.usart1.cr1.modify(|_, w| w.txeie().clear_bit()),//nothing left to send
+                }
+            }
+        }
+    }
+
+    ///transmit side of the buffered driver
+    pub struct Tx {
+        tx: Producer<'static, u8, TX_LEN>,
+    }
+
+    impl Tx {
+        ///queue bytes for transmission, returns how many fit
+        pub fn write(&mut self, bytes: &[u8]) -> usize {
+            let mut n = 0;
+            for byte in bytes {
+                if self.tx.enqueue(*byte).is_err() {
+                    break;
+                }
+                n += 1;
+            }
+
+            if n > 0 {
+                Self::start();
+            }
+            n
+        }
+
+        ///queue all the bytes or none of them, so frames are never cut short
+        pub fn write_all(&mut self, bytes: &[u8]) -> bool {
+            if self.free() < bytes.len() {
+                return false;
+            }
+
+            self.write(bytes);
+            true
+        }
+
+        ///space left in the tx queue
+        pub fn free(&self) -> usize {
+            self.tx.capacity() - self.tx.len()
+        }
+
+        ///nothing waiting in the tx queue
+        pub fn is_idle(&self) -> bool {
+            self.tx.len() == 0
+        }
+
+        fn start() {
+            let usart1 = unsafe { &(*USART1::ptr()) };//To enable use w/out ownership
+            //the interrupt only ever clears txeie so setting it here can not undo anything
+            usart1.cr1.modify(|_, w| w.txeie().set_bit());//usart interrupt when txe set
+        }
+    }
+
+    impl core::fmt::Write for Tx {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            if self.write_all(s.as_bytes()) { Ok(()) } else { Err(core::fmt::Error) }
+        }
+    }
+
+    pub const DMA_LEN: usize = 256;
+
+    pub type DmaBuffer = [u8; DMA_LEN];
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DmaError {
+        Busy,//previous transfer still running
+        TooLong,//message larger than the dma buffer
+    }
+
+    fn dma_busy() -> bool {
+        let dma1 = unsafe { &(*DMA1::ptr()) };//To enable use w/out ownership
+        dma1.ccr4.read().en().bit_is_set()
+    }
+
+    ///usart1 transmission through dma1 channel 4
+    ///a whole message is handed over at once and sent without the cpu
+    pub struct TxDma {
+        dma1: DMA1,
+        buf: &'static mut DmaBuffer,
+    }
+
+    impl TxDma {
+        pub fn config(clocks: &Clocks, dma1: DMA1, buf: &'static mut DmaBuffer) -> Self {
+            //Enable clock to dma1
+            clocks.rcc.ahbenr.modify(|_, w| w.dma1en().set_bit());
+
+            let usart1 = unsafe { &(*USART1::ptr()) };//To enable use w/out ownership
+
+            dma1.cpar4.write(|w| unsafe { w.pa().bits(&usart1.dr as *const _ as u32) });//peripheral address, usart1 data register
+            dma1.cmar4.write(|w| unsafe { w.ma().bits(buf.as_ptr() as u32) });//memory address, the buffer
+            dma1.ccr4.write(|w| unsafe { w
+                .dir().set_bit()//read from memory
+                    .minc().set_bit()//step through the buffer
+                    .pinc().clear_bit()//always the data register
+                    .msize().bits(0b00)//8 bit
+                    .psize().bits(0b00)//8 bit
+                    .pl().bits(0b01)//medium priority
+                    .tcie().set_bit()//interrupt on transfer complete
+            });
+
+            usart1.cr3.modify(|_, w| w.dmat().set_bit());//txe requests dma
+
+            TxDma { dma1, buf }
+        }
+
+        pub fn is_busy(&self) -> bool {
+            self.dma1.ccr4.read().en().bit_is_set()
+        }
+
+        ///copy the message into the dma buffer and start sending it
+        pub fn write(&mut self, bytes: &[u8]) -> Result<(), DmaError> {
+            if self.is_busy() {
+                return Err(DmaError::Busy);
+            }
+            if bytes.len() > DMA_LEN {
+                return Err(DmaError::TooLong);
+            }
+            if bytes.is_empty() {
+                return Ok(());//nothing to send, a zero count would not complete
+            }
+
+            self.buf[..bytes.len()].copy_from_slice(bytes);
+            compiler_fence(Ordering::Release);//buffer written before dma reads it
+
+            self.dma1.cndtr4.write(|w| unsafe { w.ndt().bits(bytes.len() as u16) });
+            self.dma1.ccr4.modify(|_, w| w.en().set_bit());//start transfer
+            Ok(())
+        }
+
+        ///service the dma1 channel 4 interrupt
+        ///frees the channel and hands the transmitter back to the tx queue
+        pub fn on_complete(&mut self) {
+            self.dma1.ifcr.write(|w| w.cgif4().set_bit());//clear channel 4 flags
+            self.dma1.ccr4.modify(|_, w| w.en().clear_bit());//channel free for the next message
+            compiler_fence(Ordering::Acquire);
+
+            Tx::start();//send whatever was queued during the transfer
+        }
+    }
+}
+
+pub mod led {
+    use embedded_hal::digital::{ErrorType, OutputPin, PinState};
+
+    ///indication led, wired active low
+    pub struct Led<PIN> {
+        pin: PIN,
+        lit: bool,
+    }
+
+    impl<PIN: OutputPin> Led<PIN> {
+        pub fn new(mut pin: PIN) -> Self {
+            pin.set_high().ok();//Put OFF initially
+
+            Led { pin, lit: false }
         }
 
         pub fn toggle(&mut self) {
-            self.portc.odr.modify(|r, w| w.odr13().bit(!r.odr13().bit()));
+            if self.lit { self.off() } else { self.on() }
         }
 
         pub fn on(&mut self) {
-            self.portc.odr.modify(|_, w| w.odr13().clear_bit());
+            self.pin.set_low().ok();
+            self.lit = true;
         }
 
         pub fn off(&mut self) {
-            self.portc.odr.modify(|_, w| w.odr13().set_bit());
+            self.pin.set_high().ok();
+            self.lit = false;
+        }
+    }
+
+    impl<PIN: OutputPin> ErrorType for Led<PIN> {
+        type Error = PIN::Error;
+    }
+
+    ///drives the pin directly, the led is active low so set_low turns it on
+    impl<PIN: OutputPin> OutputPin for Led<PIN> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.pin.set_low()?;
+            self.lit = true;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.pin.set_high()?;
+            self.lit = false;
+            Ok(())
+        }
+
+        fn set_state(&mut self, state: PinState) -> Result<(), Self::Error> {
+            match state {
+                PinState::Low => self.set_low(),
+                PinState::High => self.set_high(),
+            }
         }
     }
 }
 
 pub mod pwm_mod {
-    pub use super::clocks::Clocks;
+    pub use super::clocks::{prescaler, Clocks};
+    use core::convert::Infallible;
+    use embedded_hal::pwm::{ErrorType, SetDutyCycle};
+    use rover_core::servo::{ServoCalibration, SlewRate, Travel};
     use stm32f103_pac::TIM2;
 
+    ///counter clock, servo pulses are worked out against it
+    const TICK_HZ: u32 = 2_000_000;
+
     pub struct Pwm {
         tim: TIM2,
     }
@@ -164,8 +649,8 @@ pub mod pwm_mod {
                                       .tim2en().set_bit()//enable timer 1
                                       );
 
-            self.tim.psc.write(|w| unsafe { w.psc().bits(35) });//PSC = 36. FCLK = 1MHz. T = 1us
-            self.tim.arr.write(|w| unsafe { w.arr().bits(20000) });//Set PWM freq at 50Hz. T = 20ms. ARR = 20000
+            self.tim.psc.write(|w| unsafe { w.psc().bits(prescaler(clocks.timclk1(), TICK_HZ)) });//2MHz, T = 0.5us
+            self.tim.arr.write(|w| unsafe { w.arr().bits(20000) });//Set PWM period at 10ms. ARR = 20000
 
             //CH 2 -> pa1
             self.tim.ccmr1_output().modify(|_, w| unsafe { w
@@ -204,201 +689,424 @@ pub mod pwm_mod {
         }
 
         pub fn set_motor_duty(&mut self, duty: u16) {
-            use micromath::F32Ext;
-
-            let ccr: f32 = ((duty as f32/100.)*20000.).floor();
+            let arr = self.tim.arr.read().arr().bits() as u32;
+            let ccr = arr * duty.min(100) as u32 / 100;
             self.tim.ccr2.modify(|_, w| unsafe { w
                 .ccr2()
                     .bits(ccr as u16) });//ccr = (duty/100)*arr
         }
 
-        pub fn set_servo_duty(&mut self, duty: u16) {
-            use micromath::F32Ext;
+        ///CH 4 as an angle driven servo, starts centred. Call after configure, at power up
+        ///the servo may be anywhere so it is given a full swing to get there
+        ///clock is uptime in ms, for duty cycle moves which carry no time of their own
+        pub fn servo(&mut self, calibration: ServoCalibration, rate: SlewRate, clock: fn() -> u32) -> Servo {
+            let mut servo = Servo { calibration, rate, travel: Travel::new(90, rate.travel_ms(0, 180)), clock };
+            servo.set_pulse_us(calibration.pulse_us(90));
+            servo
+        }
 
-            let ccr: f32 = ((duty as f32/100.)*20000.).floor();
-            self.tim.ccr4.modify(|_, w| unsafe { w
-                .ccr4()
-                    .bits(ccr as u16) });//ccr = (duty/100)*arr
+        ///CH 2, motor drives
+        pub fn motor_channel(&mut self) -> MotorChannel<'_> {
+            MotorChannel { tim: &self.tim }
+        }
+    }
+
+    ///sensor servo on CH 4, positioned in degrees with 1us pulse resolution. The only thing that drives CH 4,
+    ///so the angle and arrival estimate always match the pulse
+    ///0 right, 90 middle, 180 left. Times are uptime in ms, the servo estimates
+    ///when it gets where it was sent from the angle it turns through and its slew rate
+    pub struct Servo {
+        calibration: ServoCalibration,
+        rate: SlewRate,
+        travel: Travel,//angle and estimated arrival
+        clock: fn() -> u32,
+    }
+
+    impl Servo {
+        ///angles past 180 are clamped
+        pub fn set_angle(&mut self, degrees: u16, now_ms: u32) {
+            let degrees = degrees.min(180);
+
+            self.travel.move_to(degrees, now_ms, self.rate);
+            self.set_pulse_us(self.calibration.pulse_us(degrees));
+        }
+
+        ///one step of a slew limited move, finish the move with set_angle so the estimate allows for settling
+        pub fn step_angle(&mut self, degrees: u16, now_ms: u32) {
+            let degrees = degrees.min(180);
+
+            self.travel.step_to(degrees, now_ms, self.rate);
+            self.set_pulse_us(self.calibration.pulse_us(degrees));
+        }
+
+        ///angle last set, the servo may still be on its way there
+        pub fn angle(&self) -> u16 {
+            self.travel.angle()
+        }
+
+        ///time until the servo has reached angle and settled, ms
+        pub fn remaining_ms(&self, now_ms: u32) -> u32 {
+            self.travel.remaining_ms(now_ms)
+        }
+
+        pub fn in_position(&self, now_ms: u32) -> bool {
+            self.travel.in_position(now_ms)
+        }
+
+        pub fn calibration(&self) -> ServoCalibration {
+            self.calibration
+        }
+
+        ///takes effect straight away, the servo moves to the current angle under the new calibration
+        ///how far is not known, so it is given a full swing
+        pub fn set_calibration(&mut self, calibration: ServoCalibration, now_ms: u32) {
+            self.calibration = calibration;
+
+            self.travel.lost(now_ms, self.rate);
+            self.set_pulse_us(calibration.pulse_us(self.angle()));
+        }
+
+        pub fn rate(&self) -> SlewRate {
+            self.rate
+        }
+
+        ///how fast this servo turns, used for the arrival estimates
+        pub fn set_rate(&mut self, rate: SlewRate) {
+            self.rate = rate;
+        }
+
+        fn set_pulse_us(&mut self, us: u16) {
+            let tim2 = unsafe { &(*TIM2::ptr()) };//To enable use w/out ownership, the servo only touches ccr4
+            let ccr = u32::from(us) * (TICK_HZ / 1_000_000);//2 ticks per us
+
+            tim2.ccr4.modify(|_, w| unsafe { w.ccr4().bits(ccr as u16) });
+        }
+    }
+
+    impl ErrorType for Servo {
+        type Error = Infallible;
+    }
+
+    ///raw pulse width in timer ticks out of arr. Pulses outside the calibration are clamped to it
+    ///and the angle is worked back from the pulse, to the nearest degree
+    impl SetDutyCycle for Servo {
+        fn max_duty_cycle(&self) -> u16 {
+            let tim2 = unsafe { &(*TIM2::ptr()) };
+            tim2.arr.read().arr().bits()
+        }
+
+        fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+            let (min_us, max_us) = (self.calibration.min_us(), self.calibration.max_us());
+            let ticks_per_us = (TICK_HZ / 1_000_000) as u16;
+            let us = (duty.min(self.max_duty_cycle()) / ticks_per_us).clamp(min_us.min(max_us), min_us.max(max_us));
+
+            self.travel.move_to(self.calibration.angle(us), (self.clock)(), self.rate);
+            self.set_pulse_us(us);
+            Ok(())
+        }
+    }
+
+    ///tim2 channel 2 handle, duty in timer ticks out of arr
+    pub struct MotorChannel<'a> {
+        tim: &'a TIM2,
+    }
+
+    impl ErrorType for MotorChannel<'_> {
+        type Error = Infallible;
+    }
+
+    impl SetDutyCycle for MotorChannel<'_> {
+        fn max_duty_cycle(&self) -> u16 {
+            self.tim.arr.read().arr().bits()
+        }
+
+        fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+            self.tim.ccr2.modify(|_, w| unsafe { w.ccr2().bits(duty) });
+            Ok(())
         }
     }
 }
 
 pub mod pins {
+    //!typestate gpio. A pin's mode is part of its type, so a driver states the mode it needs
+    //!and the board layout lives only where the pins are handed out
     use super::clocks::Clocks;
-    use stm32f103_pac::{RCC, GPIOA, GPIOB};
+    use super::functions::MotorOutputs;
+    use core::convert::Infallible;
+    use core::marker::PhantomData;
+    use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+    use stm32f103_pac::{gpioa::RegisterBlock, AFIO, EXTI, GPIOA, GPIOB, GPIOC};
+
+    pub struct Floating;
+    pub struct PushPull;
+    pub struct Input<MODE>(PhantomData<MODE>);
+    pub struct Output<MODE>(PhantomData<MODE>);
+    pub struct Alternate<MODE>(PhantomData<MODE>);
+
+    ///pin N of port P ('A', 'B', 'C')
+    pub struct Pin<const P: char, const N: u8, MODE> {
+        _mode: PhantomData<MODE>,
+    }
 
-    pub struct GPIOBPins {
-        portb: GPIOB,
+    pub type PA<const N: u8, MODE> = Pin<'A', N, MODE>;
+    pub type PB<const N: u8, MODE> = Pin<'B', N, MODE>;
+    pub type PC<const N: u8, MODE> = Pin<'C', N, MODE>;
+
+    ///the 16 pins of a port in their reset state
+    ///pa13-15, pb3 and pb4 start out as jtag pins even though they are typed as inputs
+    pub struct Parts<const P: char> {
+        pub p0: Pin<P, 0, Input<Floating>>,
+        pub p1: Pin<P, 1, Input<Floating>>,
+        pub p2: Pin<P, 2, Input<Floating>>,
+        pub p3: Pin<P, 3, Input<Floating>>,
+        pub p4: Pin<P, 4, Input<Floating>>,
+        pub p5: Pin<P, 5, Input<Floating>>,
+        pub p6: Pin<P, 6, Input<Floating>>,
+        pub p7: Pin<P, 7, Input<Floating>>,
+        pub p8: Pin<P, 8, Input<Floating>>,
+        pub p9: Pin<P, 9, Input<Floating>>,
+        pub p10: Pin<P, 10, Input<Floating>>,
+        pub p11: Pin<P, 11, Input<Floating>>,
+        pub p12: Pin<P, 12, Input<Floating>>,
+        pub p13: Pin<P, 13, Input<Floating>>,
+        pub p14: Pin<P, 14, Input<Floating>>,
+        pub p15: Pin<P, 15, Input<Floating>>,
     }
 
-    impl GPIOBPins {
-        pub fn new(clocks: &Clocks, portb: GPIOB) -> Self {
-            //Enable port b peripheral ie GPIOB
-            clocks.rcc.apb2enr.modify(|_, w| w.iopben().set_bit());
+    impl<const P: char> Parts<P> {
+        fn new() -> Self {
+            Parts {
+                p0: Pin::new(), p1: Pin::new(), p2: Pin::new(), p3: Pin::new(),
+                p4: Pin::new(), p5: Pin::new(), p6: Pin::new(), p7: Pin::new(),
+                p8: Pin::new(), p9: Pin::new(), p10: Pin::new(), p11: Pin::new(),
+                p12: Pin::new(), p13: Pin::new(), p14: Pin::new(), p15: Pin::new(),
+            }
+        }
+    }
+
+    ///split a port into its pins
+    pub trait GpioExt {
+        type Parts;
+        fn split(self, clocks: &Clocks) -> Self::Parts;
+    }
 
-            GPIOBPins { portb }
+    impl GpioExt for GPIOA {
+        type Parts = Parts<'A'>;
+
+        fn split(self, clocks: &Clocks) -> Self::Parts {
+            clocks.rcc.apb2enr.modify(|_, w| w.iopaen().set_bit());//Enable port a peripheral ie GPIOA
+            Parts::new()
         }
+    }
 
-        pub fn enable_trigger_pin(&mut self) {
-            //Configure pin 10 as trigger output
-            self.portb.crh
-                .modify(|_, w| unsafe {
-                    w.mode10().bits(0b10)//Output mode, max speed 2 MHz.
-                        .cnf10().bits(0b01)//General purpose output push-pull
-                });
+    impl GpioExt for GPIOB {
+        type Parts = Parts<'B'>;
 
-            self.portb.odr.modify(|_, w| w.odr10().clear_bit());//Put OFF initially
+        fn split(self, clocks: &Clocks) -> Self::Parts {
+            clocks.rcc.apb2enr.modify(|_, w| w.iopben().set_bit());//Enable port b peripheral ie GPIOB
+            Parts::new()
         }
+    }
+
+    impl GpioExt for GPIOC {
+        type Parts = Parts<'C'>;
 
-        pub fn trigger_toggle(&mut self) {
-            self.portb.odr.modify(|r, w| w.odr10().bit(!r.odr10().bit()));
+        fn split(self, clocks: &Clocks) -> Self::Parts {
+            clocks.rcc.apb2enr.modify(|_, w| w.iopcen().set_bit());//Enable port c peripheral ie GPIOC
+            Parts::new()
         }
+    }
 
-        pub fn trigger_high(&mut self) {
-            self.portb.odr.modify(|_, w| w.odr10().set_bit());
+    impl<const P: char, const N: u8, MODE> Pin<P, N, MODE> {
+        const fn new() -> Self {
+            Pin { _mode: PhantomData }
         }
 
-        pub fn trigger_low(&mut self) {
-            self.portb.odr.modify(|_, w| w.odr10().clear_bit());
+        fn port() -> &'static RegisterBlock {
+            let port = match P {
+                'A' => GPIOA::ptr(),
+                'B' => GPIOB::ptr() as *const RegisterBlock,
+                _ => GPIOC::ptr() as *const RegisterBlock,
+            };
+            unsafe { &*port }//To enable use w/out ownership, each pin only touches its own bits
         }
-    }
 
-    pub struct GPIOAPins {
-        porta: GPIOA,
-    }
+        ///write the 4 bit cnf/mode field of the pin
+        fn configure(cnf_mode: u32) {
+            let port = Self::port();
+            let shift = u32::from(N % 8) * 4;
 
-    impl GPIOAPins {
-        pub fn new(clocks: &Clocks, porta: GPIOA) -> Self {
-            //Enable port a peripheral ie GPIOA
-            clocks.rcc.apb2enr.modify(|_, w| w.iopaen().set_bit());
+            let set = |r: u32| (r & !(0b1111 << shift)) | (cnf_mode << shift);
+            if N < 8 {
+                port.crl.modify(|r, w| unsafe { w.bits(set(r.bits())) });
+            } else {
+                port.crh.modify(|r, w| unsafe { w.bits(set(r.bits())) });
+            }
+        }
 
-            GPIOAPins { porta }
+        pub fn into_floating_input(self) -> Pin<P, N, Input<Floating>> {
+            Self::configure(0b01_00);//Floating input, Input mode
+            Pin::new()
         }
 
-        pub fn enable_usart_pins(&self) {
-            //Configure pa9 and pa10 as tx and rx pins respectively
-            //Configure pin pa9 -> tx
-            self.porta.crh.modify(|_, w| unsafe { w
-                .mode9().bits(0b11)//Output mode, max speed 10MHz
-                    .cnf9().bits(0b10)//Alternate function output, push-pull
-            });
+        pub fn into_push_pull_output(self) -> Pin<P, N, Output<PushPull>> {
+            Self::configure(0b00_10);//General purpose output push-pull, max speed 2 MHz
+            Pin::new()
+        }
 
-            //Configure pin pa10 -> rx
-            self.porta.crh.modify(|_, w| unsafe { w
-                .mode10().bits(0b00)//Input mode
-                    .cnf10().bits(0b01)//Floating input, reset state
-            });
+        pub fn into_alternate_push_pull(self) -> Pin<P, N, Alternate<PushPull>> {
+            Self::configure(0b10_11);//Alternate function output push-pull, max speed 50 MHz
+            Pin::new()
         }
+    }
 
-        pub fn enable_pwm_pins(&self) {
-            //Configure pin pa1 -> motor drives.T2C2
-            self.porta.crl.modify(|_, w| unsafe { w
-                .mode1().bits(0b11)//Output mode, max speed 50MHz
-                    .cnf1().bits(0b10)//Alternate function output, push-pull
-            });
+    impl<const P: char, const N: u8, MODE> Pin<P, N, Output<MODE>> {
+        pub fn set_high(&mut self) {
+            Self::port().bsrr.write(|w| unsafe { w.bits(1 << N) });//atomic set
+        }
 
+        pub fn set_low(&mut self) {
+            Self::port().bsrr.write(|w| unsafe { w.bits(1 << (N + 16)) });//atomic reset
+        }
 
-            //Configure pin pa3 -> servo.T2C4
-            self.porta.crl.modify(|_, w| unsafe { w
-                .mode3().bits(0b11)//Output mode, max speed 50MHz
-                    .cnf3().bits(0b10)//Alternate function output, push-pull
-            });
+        pub fn is_set_high(&self) -> bool {
+            Self::port().odr.read().bits() & (1 << N) != 0
         }
 
-        pub fn enable_echo_pin(&self) {
-            //Configure pin 8 as t1c1
-            self.porta.crh
-                .modify(|_, w| unsafe {
-                    w.mode8().bits(0b00)//Input mode.
-                        .cnf8().bits(0b01)//Floating input (reset state)
-                });
+        pub fn toggle(&mut self) {
+            if self.is_set_high() { self.set_low() } else { self.set_high() }
+        }
+    }
+
+    ///exti trigger
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Edge {
+        Rising,
+        Falling,
+        RisingFalling,
+    }
+
+    impl<const P: char, const N: u8, MODE> Pin<P, N, Input<MODE>> {
+        ///level on the pin, read from idr
+        pub fn is_high(&self) -> bool {
+            Self::port().idr.read().bits() & (1 << N) != 0
         }
 
         pub fn is_low(&self) -> bool {
-            //Check whether echo pin 8 is low
-            self.porta.odr.read().odr8().bit_is_clear()
+            !self.is_high()
         }
 
-        pub fn is_high(&self) -> bool {
-            //Check whether echo pin 8 is high
-            self.porta.odr.read().odr8().bit_is_set()
+        ///route the pin to exti line N. The afio clock must be on
+        pub fn make_interrupt_source(&mut self, afio: &AFIO) {
+            let shift = u32::from(N % 4) * 4;
+            let port = match P {
+                'A' => 0b0000,
+                'B' => 0b0001,
+                _ => 0b0010,
+            };
+
+            let set = |r: u32| (r & !(0b1111 << shift)) | (port << shift);
+            match N / 4 {
+                0 => afio.exticr1.modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+                1 => afio.exticr2.modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+                2 => afio.exticr3.modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+                _ => afio.exticr4.modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+            }
         }
 
-    }
+        pub fn trigger_on_edge(&mut self, exti: &EXTI, edge: Edge) {
+            let rising = matches!(edge, Edge::Rising | Edge::RisingFalling);
+            let falling = matches!(edge, Edge::Falling | Edge::RisingFalling);
 
-    pub struct ShiftRegisterPins;
-    
-    impl ShiftRegisterPins {
-        pub fn configure() -> Self {
-            let rcc = unsafe { &(*RCC::ptr()) };
-            rcc.apb2enr.modify(|_, w| w.iopben().set_bit());//enable clock
+            exti.rtsr.modify(|r, w| unsafe { w.bits(if rising { r.bits() | 1 << N } else { r.bits() & !(1 << N) }) });
+            exti.ftsr.modify(|r, w| unsafe { w.bits(if falling { r.bits() | 1 << N } else { r.bits() & !(1 << N) }) });
+        }
 
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            
-            //Configure pin 13 as latch pin
-            portb.crh
-                .modify(|_, w| unsafe {
-                    w.mode13().bits(0b01)//Output mode, max speed 10 MHz.
-                        .cnf13().bits(0b00)//General purpose output push-pull
-                });
+        pub fn enable_interrupt(&mut self, exti: &EXTI) {
+            exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | 1 << N) });//unmask line N
+        }
+
+        pub fn disable_interrupt(&mut self, exti: &EXTI) {
+            exti.imr.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << N)) });
+        }
+
+        pub fn check_interrupt(&self) -> bool {
+            let exti = unsafe { &(*EXTI::ptr()) };//To enable use w/out ownership
+            exti.pr.read().bits() & (1 << N) != 0
+        }
 
-            portb.odr.modify(|_, w| w.odr13().clear_bit());//Put OFF initially
+        pub fn clear_interrupt_pending_bit(&mut self) {
+            let exti = unsafe { &(*EXTI::ptr()) };//To enable use w/out ownership
+            exti.pr.write(|w| unsafe { w.bits(1 << N) });//write 1 to clear
+        }
+    }
 
-            //Configure pin 14 as data pin
-            portb.crh
-                .modify(|_, w| unsafe {
-                    w.mode14().bits(0b01)//Output mode, max speed 10 MHz.
-                        .cnf14().bits(0b00)//General purpose output push-pull
-                });
+    impl<const P: char, const N: u8, MODE> ErrorType for Pin<P, N, MODE> {
+        type Error = Infallible;
+    }
 
-            portb.odr.modify(|_, w| w.odr14().clear_bit());//Put OFF initially
+    impl<const P: char, const N: u8, MODE> OutputPin for Pin<P, N, Output<MODE>> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            Pin::set_low(self);
+            Ok(())
+        }
 
-            //Configure pin 12 as clock pin
-            portb.crh
-                .modify(|_, w| unsafe {
-                    w.mode12().bits(0b01)//Output mode, max speed 10 MHz.
-                        .cnf12().bits(0b00)//General purpose output push-pull
-                });
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            Pin::set_high(self);
+            Ok(())
+        }
+    }
 
-            portb.odr.modify(|_, w| w.odr12().clear_bit());//Put OFF initially
+    impl<const P: char, const N: u8, MODE> StatefulOutputPin for Pin<P, N, Output<MODE>> {
+        fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(Pin::is_set_high(self))
+        }
 
-            Self
+        fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!Pin::is_set_high(self))
         }
+    }
 
-        pub fn latch_high() {
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            portb.odr.modify(|_, w| w.odr13().set_bit());
+    impl<const P: char, const N: u8, MODE> InputPin for Pin<P, N, Input<MODE>> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(Pin::is_high(self))
         }
 
-        pub fn latch_low() {
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            portb.odr.modify(|_, w| w.odr13().clear_bit());
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(Pin::is_low(self))
         }
+    }
+
+    ///latch, data and clock lines of the motor shift register
+    pub struct ShiftRegisterPins<L, D, C> {
+        latch: L,
+        data: D,
+        clock: C,
+    }
 
-        pub fn data_high() {
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            portb.odr.modify(|_, w| w.odr14().set_bit());
+    impl<L: OutputPin, D: OutputPin, C: OutputPin> ShiftRegisterPins<L, D, C> {
+        pub fn new(latch: L, data: D, clock: C) -> Self {
+            ShiftRegisterPins { latch, data, clock }
         }
+    }
 
-        pub fn data_low() {
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            portb.odr.modify(|_, w| w.odr14().clear_bit());
+    impl<L: OutputPin, D: OutputPin, C: OutputPin> MotorOutputs for ShiftRegisterPins<L, D, C> {
+        fn latch(&mut self, high: bool) {
+            self.latch.set_state(high.into()).ok();
         }
 
-        pub fn clock_high() {
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            portb.odr.modify(|_, w| w.odr12().set_bit());
+        fn data(&mut self, high: bool) {
+            self.data.set_state(high.into()).ok();
         }
 
-        pub fn clock_low() {
-            let portb = unsafe { &(*GPIOB::ptr()) };
-            portb.odr.modify(|_, w| w.odr12().clear_bit());
+        fn clock(&mut self, high: bool) {
+            self.clock.set_state(high.into()).ok();
         }
     }
 }
 
 pub mod input_capture {
-    use super::clocks::Clocks;
+    use super::clocks::{prescaler, Clocks};
     use stm32f103_pac::TIM1;
 
     pub struct InputCapture {
@@ -410,7 +1118,7 @@ pub mod input_capture {
             //Enable clock to timer1
             clocks.rcc.apb2enr.modify(|_, w| w.tim1en().set_bit());   
 
-            tim.psc.modify(|_, w| unsafe { w.psc().bits(71) });//psc = 72. Set fCLK to 1MHz, T = 1us
+            tim.psc.modify(|_, w| unsafe { w.psc().bits(prescaler(clocks.timclk2(), 1_000_000)) });//Set fCLK to 1MHz, T = 1us
             tim.arr.modify(|_, w| unsafe { w.arr().bits(0xFFFF)});//Set arr to max
             tim.cr1.modify(|_, w| w.arpe().set_bit());//enable arr auto reload
 
@@ -448,6 +1156,17 @@ pub mod input_capture {
             self.tim.ccr1.read().ccr1().bits()
         }
 
+        ///give up on a measurement, wait for the next rising edge
+        pub fn reset(&self) {
+            self.disable();
+            self.tim.ccer.modify(|_, w| w
+                                 .cc1e().clear_bit()//first disable capture
+                                 .cc1p().clear_bit()//back to the rising edge
+                                 .cc1e().set_bit()//Capture enabled
+                                 );
+            self.tim.sr.modify(|_, w| w.cc1if().clear_bit().cc1of().clear_bit());
+        }
+
         pub fn enable_cc1ie_interrupt(&self) {
             self.tim.dier.modify(|_, w| w.cc1ie().set_bit());//CC1 interrupt enabled
         }
@@ -492,12 +1211,143 @@ pub mod input_capture {
             self.tim.cnt.read().bits()
         }
     }
+
+    ///tim1 in pwm input mode. Both channels capture ti1, the rising edge resets the counter
+    ///so ccr2 holds the pulse width once the falling edge arrives, no polarity switching or overflow counting
+    pub struct PwmInput {
+        tim: TIM1,
+    }
+
+    impl PwmInput {
+        pub fn configure(clocks: &Clocks, tim: TIM1) -> Self {
+            //Enable clock to timer1
+            clocks.rcc.apb2enr.modify(|_, w| w.tim1en().set_bit());
+
+            tim.psc.modify(|_, w| unsafe { w.psc().bits(prescaler(clocks.timclk2(), 1_000_000)) });//Set fCLK to 1MHz, T = 1us
+            tim.arr.modify(|_, w| unsafe { w.arr().bits(0xFFFF)});//Set arr to max, longest echo is 38ms
+            tim.egr.write(|w| w.ug().set_bit());//Force update of registers
+            tim.sr.modify(|_, w| w.uif().clear_bit());//Clear update flag
+
+            tim.ccmr1_input().modify(|_, w| unsafe { w
+                                     .cc1s().bits(0b01)//IC1 is mapped on TI1
+                                     .cc2s().bits(0b10)//IC2 is mapped on TI1
+                                     });
+            tim.ccer.modify(|_, w| w
+                            .cc1p().clear_bit()//IC1 captures the rising edge
+                            .cc2p().set_bit()//IC2 captures the falling edge
+                            );
+            tim.smcr.modify(|_, w| unsafe { w
+                            .ts().bits(0b101)//trigger on TI1FP1
+                            .sms().bits(0b100)//slave reset mode, rising edge reinitialises the counter
+                            });
+            tim.ccer.modify(|_, w| w
+                            .cc1e().set_bit()//Capture enabled
+                            .cc2e().set_bit()
+                            );
+
+            PwmInput { tim }
+        }
+
+        pub fn enable(&self) {
+            self.tim.cr1.modify(|_, w| w.cen().set_bit());//enable counter
+        }
+
+        pub fn disable(&self) {
+            self.tim.cr1.modify(|_, w| w.cen().clear_bit());//disable counter
+        }
+
+        ///pulse width in us
+        pub fn read_pulse(&self) -> u16 {
+            self.tim.ccr2.read().ccr2().bits()
+        }
+
+        ///time between the last two rising edges in us
+        pub fn read_period(&self) -> u16 {
+            self.tim.ccr1.read().ccr1().bits()
+        }
+
+        pub fn enable_interrupt(&self) {
+            self.tim.dier.modify(|_, w| w.cc2ie().set_bit());//CC2 interrupt enabled, fires at the falling edge
+        }
+
+        pub fn disable_interrupt(&self) {
+            self.tim.dier.modify(|_, w| w.cc2ie().clear_bit());//CC2 interrupt disabled
+        }
+
+        pub fn is_capture(&self) -> bool {
+            self.tim.sr.read().cc2if().bit()//checks if a falling edge was captured
+        }
+
+        pub fn clear_capture(&self) {
+            self.tim.sr.modify(|_, w| w
+                               .cc1if().clear_bit()
+                               .cc2if().clear_bit()
+                               .cc1of().clear_bit()
+                               .cc2of().clear_bit()
+                               );
+        }
+    }
 }
 
-pub mod delay {
+pub mod echo_exti {
+    //!echo pulse timing from edge interrupts, for boards where tim1 ch1 is taken
+    //!edges are timestamped with the dwt cycle counter, which must be running
     use super::clocks::Clocks;
+    use super::pins::{Edge, Floating, Input, Pin};
+    use cortex_m::peripheral::DWT;
+    use stm32f103_pac::{AFIO, EXTI};
+
+    pub struct ExtiEcho<const P: char, const N: u8> {
+        pin: Pin<P, N, Input<Floating>>,
+        rise: Option<u32>,//cycle count at the rising edge
+        cycles_per_us: u32,
+    }
+
+    impl<const P: char, const N: u8> ExtiEcho<P, N> {
+        pub fn new(clocks: &Clocks, mut pin: Pin<P, N, Input<Floating>>, afio: &AFIO, exti: &EXTI) -> Self {
+            clocks.rcc.apb2enr.modify(|_, w| w.afioen().set_bit());//Enable clock to afio
+
+            pin.make_interrupt_source(afio);
+            pin.trigger_on_edge(exti, Edge::RisingFalling);
+            pin.clear_interrupt_pending_bit();
+            pin.enable_interrupt(exti);
+
+            ExtiEcho { pin, rise: None, cycles_per_us: clocks.hclk() / 1_000_000 }
+        }
+
+        ///service the exti interrupt, returns the pulse width in us at the falling edge
+        pub fn on_interrupt(&mut self) -> Option<u32> {
+            let now = DWT::cycle_count();
+            self.pin.clear_interrupt_pending_bit();
+
+            if self.pin.is_high() {
+                self.rise = Some(now);//echo started
+                None
+            } else {
+                self.rise.take().map(|rise| now.wrapping_sub(rise) / self.cycles_per_us)
+            }
+        }
+
+        ///true if an edge raised the interrupt, false if it was pended by software
+        pub fn is_pending(&self) -> bool {
+            self.pin.check_interrupt()
+        }
+
+        ///give up on a measurement, forget the rising edge
+        pub fn reset(&mut self) {
+            self.rise = None;
+        }
+    }
+}
+
+pub mod delay {
+    use super::clocks::{prescaler, Clocks};
+    use embedded_hal::delay::DelayNs;
     use stm32f103_pac::{TIM3, TIM4};
 
+    ///longest delay that fits in arr at 10 ticks per ms
+    const MAX_CHUNK_MS: u16 = 6000;
+
     pub struct DelayMs;
 
     impl DelayMs {
@@ -511,12 +1361,10 @@ pub mod delay {
                           //.urs().set_bit()//Only counter overflow/underflow generates an update interrupt
                           );
 
-            // Configure the prescaler to have the counter operate at 1 KHz
-            // APB1_CLOCK = 36 MHz. TIM4CLK = 36 x 2 = 72MHz b/c APB1 PSC != 1
-            // PSC = 7199
-            // 72 MHz / (7199 + 1) = 10 KHz
-            // The counter (CNT) will increase on every 10 milliseconds
-            tim.psc.write(|w| unsafe { w.psc().bits(7199) });
+            // Configure the prescaler to have the counter operate at 10 KHz
+            // TIM4CLK is twice APB1_CLOCK when APB1 is divided
+            // The counter (CNT) will increase on every 0.1 milliseconds
+            tim.psc.write(|w| unsafe { w.psc().bits(prescaler(clocks.timclk1(), 10_000)) });
 
             tim.egr.write(|w| w.ug().set_bit());//Force update of registers
             tim.sr.modify(|_, w| w.uif().clear_bit());//Clear update flag
@@ -525,18 +1373,26 @@ pub mod delay {
         #[inline(never)]
         pub fn delay_ms(ms: u16) {
             let tim = unsafe { &(*TIM4::ptr()) };//To enable use w/out ownership
-            // Set the timer to go off in `ms` ticks
-            // 1 tick = 10 ms
-            tim.arr.write(|w| unsafe { w.arr().bits(10*ms) });//1ms = 1000us
+            let mut remaining = ms;
 
-            tim.cr1.modify(|_, w| w.cen().set_bit());// Enable the counter
+            //arr is 16 bit so longer delays are split into chunks
+            while remaining > 0 {
+                let chunk = remaining.min(MAX_CHUNK_MS);
+                remaining -= chunk;
 
-            // Wait until the alarm goes off (until the update event occurs)
-            while !tim.sr.read().uif().bit_is_set() {}
+                // Set the timer to go off in `ms` ticks
+                // 1 tick = 0.1 ms
+                tim.arr.write(|w| unsafe { w.arr().bits(10*chunk) });//1ms = 1000us
 
-            tim.cr1.modify(|_, w| w.cen().clear_bit());//disable counter
+                tim.cr1.modify(|_, w| w.cen().set_bit());// Enable the counter
 
-            tim.sr.modify(|_, w| w.uif().clear_bit());// Clear the update event flag
+                // Wait until the alarm goes off (until the update event occurs)
+                while !tim.sr.read().uif().bit_is_set() {}
+
+                tim.cr1.modify(|_, w| w.cen().clear_bit());//disable counter
+
+                tim.sr.modify(|_, w| w.uif().clear_bit());// Clear the update event flag
+            }
         }
     }
 
@@ -553,12 +1409,10 @@ pub mod delay {
                           //.urs().set_bit()//Only counter overflow/underflow generates an update interrupt
                           );
 
-            // Configure the prescaler to have the counter operate at 1 KHz
-            // APB1_CLOCK = 36 MHz. TIM4CLK = 36 x 2 = 72MHz b/c APB1 PSC != 1
-            // PSC = 7199
-            // 72 MHz / (71 + 1) = 1 MHz
+            // Configure the prescaler to have the counter operate at 1 MHz
+            // TIM3CLK is twice APB1_CLOCK when APB1 is divided
             // The counter (CNT) will increase on every 1 microsecond
-            tim.psc.write(|w| unsafe { w.psc().bits(71) });
+            tim.psc.write(|w| unsafe { w.psc().bits(prescaler(clocks.timclk1(), 1_000_000)) });
 
             tim.egr.write(|w| w.ug().set_bit());//Force update of registers
             tim.sr.modify(|_, w| w.uif().clear_bit());//Clear update flag
@@ -581,121 +1435,59 @@ pub mod delay {
             tim.sr.modify(|_, w| w.uif().clear_bit());// Clear the update event flag
         }
     }
-}
 
-pub mod functions {
-    use super::{Data, delay::DelayMs, pins::ShiftRegisterPins};
-    use rtt_target::rprintln;
-    use super::{MOVING_FORWARD, Command::{self, Forward, Reverse, RightTurn, LeftTurn, Brake, Stop, Donut}};
-
-    pub fn drive_motors(command: &Command) {
-        let mut data;
-        //[0,   1,   2,    3,    4,   5,   6,   7]
-        //[BR2, BR1, FR2, FR1, BL2, BL1, FR2, FR1]
-
-        match command {
-            Forward => {
-                data = [1, 0, 1, 0, 1, 0, 1, 0];
-                update_shift_register(data);
-                rprintln!("forward...");
-            },
-            _ => {
-                unsafe { MOVING_FORWARD = false; }
-
-                match command {
-                    Reverse => {
-                        data = [0, 1, 0, 1, 0, 1, 0, 1];
-                        update_shift_register(data);
-                        rprintln!("reverse...");
-                    },
-                    RightTurn => { 
-                        data = [0, 1, 0, 1, 1, 0, 1, 0];
-                        update_shift_register(data);
-                        rprintln!("right turn...");
-                        DelayMs::delay_ms(250);//wait until turn finished
-                        data = [0, 0, 0, 0, 0, 0, 0, 0];
-                        update_shift_register(data);//reset shift register
-                    },
-                    LeftTurn => {
-                        data = [1, 0, 1, 0, 0, 1, 0, 1];
-                        update_shift_register(data);
-                        rprintln!("left turn...");
-                        DelayMs::delay_ms(250);//wait until turn finished
-                        data = [0, 0, 0, 0, 0, 0, 0, 0];
-                        update_shift_register(data);//reset shift register
-                    },
-                    Brake => { 
-                        data = [0, 1, 0, 1, 0, 1, 0, 1];
-                        update_shift_register(data);//hard reverse
-                        rprintln!("brake...");
-                        DelayMs::delay_ms(200);//wait until finished
-                        data = [0, 0, 0, 0, 0, 0, 0, 0];
-                        update_shift_register(data);//reset shift register
-                    },
-                    Stop => {
-                        data = [0, 0, 0, 0, 0, 0, 0, 0];
-                        update_shift_register(data);//reset shift register
-                        rprintln!("stop...");
-                    },
-                    Donut => {
-                        data = [0, 1, 0, 1, 1, 0, 1, 0];
-                        update_shift_register(data);
-                        rprintln!("Donut...");
-                        DelayMs::delay_ms(2000);//wait until donut finished
-                        data = [0, 0, 0, 0, 0, 0, 0, 0];
-                        update_shift_register(data);//reset shift register
-                    },
-                    _ => { },
-                }
-            },
+    ///rounds up to whole ms
+    impl DelayNs for DelayMs {
+        fn delay_ns(&mut self, ns: u32) {
+            DelayNs::delay_ms(self, ns.div_ceil(1_000_000));
         }
-    }
 
-    pub fn update_shift_register(data: Data) {
-        ShiftRegisterPins::latch_low();
+        fn delay_us(&mut self, us: u32) {
+            DelayNs::delay_ms(self, us.div_ceil(1000));
+        }
 
-        //Send data to SER
-        for byte in data.into_iter().rev() {
-            if byte == 1 {
-                ShiftRegisterPins::data_low();
-            } else {
-                ShiftRegisterPins::data_high();
+        fn delay_ms(&mut self, mut ms: u32) {
+            while ms > 0 {
+                let chunk = ms.min(u32::from(u16::MAX));
+                DelayMs::delay_ms(chunk as u16);
+                ms -= chunk;
             }
+        }
+    }
 
-            ShiftRegisterPins::clock_high();
-            DelayMs::delay_ms(5);//5ms delay
-            ShiftRegisterPins::clock_low();
+    ///rounds up to whole us
+    impl DelayNs for DelayUs {
+        fn delay_ns(&mut self, ns: u32) {
+            DelayNs::delay_us(self, ns.div_ceil(1000));
         }
 
-        ShiftRegisterPins::latch_high();
+        fn delay_us(&mut self, mut us: u32) {
+            //a zero arr would never raise the update flag
+            while us > 0 {
+                let chunk = us.min(u32::from(u16::MAX));
+                DelayUs::delay_us(chunk as u16);
+                us -= chunk;
+            }
+        }
     }
 }
 
+pub mod functions {
+    //!the motors on the board, the patterns for each command come from rover_core::motors
+    pub use rover_core::motors::*;
+    use super::{delay::DelayMs, pins::{Output, PushPull, ShiftRegisterPins, PB}};
+
+    ///latch pb13, data pb14, clock pb12
+    pub type BoardShiftRegisterPins = ShiftRegisterPins<PB<13, Output<PushPull>>, PB<14, Output<PushPull>>, PB<12, Output<PushPull>>>;
+    pub type BoardMotors = ShiftRegister<BoardShiftRegisterPins, DelayMs>;
+}
+
 pub enum EchoStatus {
     IDLE,
     DONE,
 }
 
-#[derive(Debug)]
-pub enum Command {
-    Forward,
-    Reverse,
-    RightTurn,
-    LeftTurn,
-    Brake,
-    Stop,
-    Donut,
-}
-
 pub enum ServoDirection {
     Right,
     Left,
 }
-
-#[derive(Debug)]
-pub enum UltrasonicPosition {
-    Right,
-    Left,
-    Middle
-}
-
@@ -10,19 +10,176 @@
 #![allow(clippy::needless_if)]
 
 use panic_rtt_target as _;
-use rtt_target::{rprint, rprintln, rtt_init_print};
-use rtic_monotonics::systick::{ExtU32, Systick};
+use rtt_target::{rprintln, rtt_init_print};
+use rtic::Mutex;
+use rtic_monotonics::{Monotonic, systick::{ExtU32, Systick}};
+use core::fmt::Write;
+#[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
+use rtt_target::rprint;//only time_capture prints without a newline
+#[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
+use obstacle_avoiding_rover::{input_capture::InputCapture, EchoStatus::{self, IDLE, DONE}, distance::pulse_us};
+#[cfg(feature = "echo-exti")]
+use obstacle_avoiding_rover::echo_exti::ExtiEcho;
+#[cfg(feature = "echo-pwm-input")]
+use obstacle_avoiding_rover::input_capture::PwmInput;
+use heapless::{spsc::{Consumer, Queue}, String};
 use obstacle_avoiding_rover::{
-    pac, clocks, led, usart1, pwm_mod, MOVING_FORWARD,
-    input_capture::InputCapture, 
-    pins::{GPIOAPins, GPIOBPins, ShiftRegisterPins}, EchoStatus::{self, IDLE, DONE}, delay::{DelayMs, DelayUs},
-    Command::{self, Forward, Reverse, RightTurn, LeftTurn, Brake, Stop, Donut}, UltrasonicPosition::{self, Right, Left, Middle}, 
-    functions::drive_motors
+    pac, clocks, led, usart1::{self, BufferedUsart1, DmaBuffer, RxQueue, TxDma, TxQueue, DMA_LEN, RX_LEN, RX_POLL_MS}, pwm_mod, protocol::{self, Decoder, Reply, Scan, Telemetry, MAX_FRAME},
+    pins::{GpioExt, Output, PushPull, ShiftRegisterPins, PB, PC}, delay::{DelayMs, DelayUs},
+    Command::{self, Stop}, UltrasonicPosition::{self, Middle}, 
+    functions::{start_motors, BoardMotors, MotorDriver, ShiftRegister}, shell::{self, LineBuffer, ShellCommand, ShellError},
+    avoidance::{Avoidance, Decision, AHEAD}, distance::{self, Distance, Fixed, Range, SpeedOfSound}, ranging::{self, Ranging}, requests,
+    scan::{self, PolarMap, Sweep}, servo::{slewed, ServoCalibration, SlewRate, STEP_MS}
 };
 
-use micromath::F32Ext;
 
-const D_STOP: u32 = 20;
+#[cfg(all(feature = "echo-exti", feature = "echo-pwm-input"))]
+compile_error!("select one echo backend, echo-exti or echo-pwm-input");
+
+const D_STOP: Distance = Distance::from_cm(20);//default stop distance
+const TELEMETRY_MS: u16 = 500;//default telemetry period
+const LINK_TIMEOUT_MS: u16 = 1000;//default link-loss timeout in manual mode
+const BAUD: u32 = 9600;//HC-06 default
+const _: () = assert!(BAUD <= usart1::MAX_BAUD, "the rx queue is sized for usart1::MAX_BAUD");
+const FRAME_TIMEOUT_MS: u32 = 2 * (MAX_FRAME as u32 * 10 * 1000).div_ceil(BAUD);//twice a whole frame at BAUD, 10 bits a byte
+const MEASURE_MS: u32 = 60;//hc-sr04 measurement cycle
+const ECHO_DEADLINE_MS: u32 = distance::ECHO_TIMEOUT_US / 1000 + 2;//longest echo plus the burst before it
+#[cfg(not(feature = "echo-exti"))]
+const ECHO_INTERRUPT: pac::Interrupt = pac::Interrupt::TIM1_CC;//both tim1 backends
+#[cfg(feature = "echo-exti")]
+const ECHO_INTERRUPT: pac::Interrupt = pac::Interrupt::EXTI9_5;
+
+///time since boot, ms
+fn uptime_ms() -> u32 {
+    Systick::now().duration_since_epoch().to_millis()
+}
+
+///uptime has reached deadline_ms, allowing for wrap
+fn reached(deadline_ms: u32) -> bool {
+    uptime_ms().wrapping_sub(deadline_ms) as i32 >= 0
+}
+
+///set motor speed for the command, capped at limit, and start it. Returns how long a timed move runs, ms
+///active keeps what the motors are doing for telemetry. Nothing is locked while the pattern shifts out
+fn drive<M, P, A>(motors: &mut M, pwm: &mut P, active: &mut A, command: &Command, limit: u8) -> Option<u32>
+where
+    M: MotorDriver,
+    P: Mutex<T = pwm_mod::Pwm>,
+    A: Mutex<T = Command>,
+{
+    pwm.lock(|pwm| pwm.set_motor_duty(u16::from(command.speed().min(limit))));
+    let run_ms = start_motors(motors, command);
+    active.lock(|active| *active = *command);
+    run_ms
+}
+
+///drive the decision's motor commands one after another, then wait out its pause
+///stops short if auto mode ends part way, manual mode brakes from there
+async fn act<M, P, C, A>(motors: &mut M, pwm: &mut P, active: &mut C, auto: &mut A, decision: &Decision, limit: u8)
+where
+    M: MotorDriver,
+    P: Mutex<T = pwm_mod::Pwm>,
+    C: Mutex<T = Command>,
+    A: Mutex<T = bool>,
+{
+    for c in decision.drive.iter().flatten() {
+        if !auto.lock(|auto| *auto) {
+            return;
+        }
+        rprintln!("auto {:?}", c);
+        if let Some(ms) = drive(motors, pwm, active, c, limit) {
+            Systick::delay(ms.millis()).await;
+            drive(motors, pwm, active, &Stop, limit);
+        }
+    }
+
+    if decision.pause_ms > 0 {
+        Systick::delay(u32::from(decision.pause_ms).millis()).await;//delay a little
+    }
+}
+
+///range at the current servo angle, filtered over several pings
+///readings that disagree count as no echo
+async fn measure<R: Mutex<T = Option<Range>>>(distance: &mut R) -> Range {
+    let mut ranging: Ranging<{ ranging::SAMPLES }> = Ranging::new();
+
+    loop {
+        distance.lock(|distance| *distance = None);
+        if app::trigger::spawn().is_err() {}
+        Systick::delay((MEASURE_MS + 10).millis()).await;//echo returned or timed out, trigger task finished
+
+        let range = distance.lock(|distance| distance.take()).unwrap_or(Range::NoEcho);
+        if let Some(filtered) = ranging.push(range) {
+            return if filtered.valid { filtered.range } else { Range::NoEcho };
+        }
+    }
+}
+
+///wait until the servo has reached the angle it was last sent to
+async fn in_position<S: Mutex<T = pwm_mod::Servo>>(servo: &mut S) {
+    loop {
+        let left_ms = servo.lock(|servo| servo.remaining_ms(uptime_ms()));
+        if left_ms == 0 {
+            return;
+        }
+        Systick::delay(left_ms.millis()).await;//another task may move it meanwhile, check again
+    }
+}
+
+///turn the sensor to angle, no faster than rate if given, and wait until it is there
+async fn turn_servo<S, U>(servo: &mut S, us_pos: &mut U, angle: u16, rate: Option<SlewRate>)
+where
+    S: Mutex<T = pwm_mod::Servo>,
+    U: Mutex<T = UltrasonicPosition>,
+{
+    if let Some(rate) = rate {
+        let from = servo.lock(|servo| servo.angle());
+        for step in slewed(from, angle, rate) {
+            servo.lock(|servo| servo.step_angle(step, uptime_ms()));
+            Systick::delay(STEP_MS.millis()).await;
+        }
+    }
+
+    (&mut *servo, &mut *us_pos).lock(|servo, us_pos| {
+        servo.set_angle(angle, uptime_ms());
+        *us_pos = scan::position(angle);
+    });
+    in_position(servo).await;
+}
+
+///point the sensor at each angle of the sweep and range it there, leaves the servo at the last angle
+///None if the mode leaves is_auto part way, the servo belongs to the other mode from then on
+async fn sweep<S, U, R, A>(servo: &mut S, us_pos: &mut U, distance: &mut R, auto: &mut A, is_auto: bool, sweep: &Sweep) -> Option<PolarMap>
+where
+    S: Mutex<T = pwm_mod::Servo>,
+    U: Mutex<T = UltrasonicPosition>,
+    R: Mutex<T = Option<Range>>,
+    A: Mutex<T = bool>,
+{
+    let mut map = PolarMap::new();
+
+    for angle in sweep.angles() {
+        if auto.lock(|auto| *auto) != is_auto {
+            rprintln!("sweep abandoned, mode changed");
+            return None;
+        }
+        turn_servo(servo, us_pos, angle, None).await;//only as long as the swing takes
+
+        let point = scan::point(angle, measure(distance).await);
+        rprintln!("sweep {}: {}", point.0, point.1);
+        map.push(point).ok();//a sweep has at most MAX_POINTS angles
+    }
+
+    (auto.lock(|auto| *auto) == is_auto).then_some(map)
+}
+
+///send console text through dma once usart1 has finished with the tx queue
+///waits for the channel instead of dropping text
+async fn print<Q: Mutex<T = usart1::Tx>, D: Mutex<T = TxDma>>(tx: &mut Q, dma: &mut D, text: &str) {
+    while !(&mut *tx, &mut *dma).lock(|tx, dma| tx.is_idle() && dma.write(text.as_bytes()).is_ok()) {
+        Systick::delay(1.millis()).await;//queued frames or an earlier transfer still going out
+    }
+}
 
 #[rtic::app(device = pac, peripherals = true, dispatchers = [USART2, TIM2])]
 mod app {
@@ -32,56 +189,116 @@ mod app {
     struct Shared {
         command: Option<Command>,
         auto: bool,
+        #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
         ov_cnt: u32,//overcount
-        distance: Option<u32>,
+        distance: Option<Range>,
+        last_distance: Option<Range>,//last measurement, kept for reporting
+        echo_pending: bool,//pinged, waiting for the echo
+        sweep_angles: Sweep,//angles visited by auto mode and scan
+        unsent_scan: Option<PolarMap>,//latest sweep, waiting to go out as telemetry
+        sound: SpeedOfSound,//echo to distance, follows the air temperature
+        #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
         ic: InputCapture,
+        tx: usart1::Tx,//usart1 transmit queue
+        dma: TxDma,//usart1 transmit dma, for console text
+        ultrasonic_pos: UltrasonicPosition,
+        active: Command,//what the motors are currently doing
+        telemetry_ms: u16,//telemetry period, 0 => off
+        pwm: pwm_mod::Pwm,
+        servo: pwm_mod::Servo,//sensor servo, tim2 ch 4
+        d_stop: Distance,//stop distance
+        speed_limit: u8,//motor speed limit, %
+        last_rx: u32,//uptime of the last valid frame, ms
+        link_timeout_ms: u16,//manual mode failsafe, 0 => off
     }
 
     #[local]
     struct Local {
-        led: led::Led,
-        usart: usart1::Usart1,
-        trigger: GPIOBPins,
+        led: led::Led<PC<13, Output<PushPull>>>,
+        decoder: Decoder,
+        line: LineBuffer,
+        air: Fixed,//conditions set from the console
+        rx: Consumer<'static, u8, RX_LEN>,
+        serial: BufferedUsart1,
+        trigger: PB<10, Output<PushPull>>,
+        motors: BoardMotors,
+        #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
         echo_status: EchoStatus,
-        pwm: pwm_mod::Pwm,
+        #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
         t1: u32,
+        #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
         t2: u32,
-        ultrasonic_pos: UltrasonicPosition,
+        #[cfg(feature = "echo-exti")]
+        echo: ExtiEcho<'A', 8>,
+        #[cfg(feature = "echo-pwm-input")]
+        pwm_input: PwmInput,
     }
 
-    #[init]
+    #[init(local = [rx_queue: RxQueue = Queue::new(), tx_queue: TxQueue = Queue::new(), dma_buf: DmaBuffer = [0; DMA_LEN]])]
     fn init(cx: init::Context) -> (Shared, Local) {
         //Initialise clocks
-        let mut clocks = clocks::Clocks::new(cx.device.RCC, cx.device.FLASH);
-        clocks.configure();
+        let mut clocks = clocks::Clocks::new(cx.device.RCC, cx.device.FLASH)
+            .hse(8_000_000)
+            .sysclk(72_000_000)
+            .pclk1_div(2);//apb1 is limited to 36MHz
+        clocks.configure().unwrap();
 
         //Initialize the systick interrupt & obtain the token to prove that we did
         let systick_mono_token = rtic_monotonics::create_systick_token!();
-        Systick::start(cx.core.SYST, 72_000_000, systick_mono_token); // default STM32F103
+        Systick::start(cx.core.SYST, clocks.hclk(), systick_mono_token);
+
+        //Pins, the board layout lives here
+        let pa = cx.device.GPIOA.split(&clocks);
+        let pb = cx.device.GPIOB.split(&clocks);
+        let pc = cx.device.GPIOC.split(&clocks);
 
-        //Enable pins
         //Usart Echo & Pwm
-        let gpioa_pins = GPIOAPins::new(&clocks, cx.device.GPIOA);
-        gpioa_pins.enable_echo_pin();//enable echo pin
-        gpioa_pins.enable_usart_pins();
-        gpioa_pins.enable_pwm_pins();
+        let echo_pin = pa.p8.into_floating_input();//echo, t1c1 or exti8
+        pa.p9.into_alternate_push_pull();//usart1 tx
+        pa.p10.into_floating_input();//usart1 rx
+        pa.p1.into_alternate_push_pull();//motor drives, t2c2
+        pa.p3.into_alternate_push_pull();//servo, t2c4
 
         //Ultrasonic pins
-        let mut gpiob_pins = GPIOBPins::new(&clocks, cx.device.GPIOB);
-        gpiob_pins.enable_trigger_pin();//enable trigger pin
-        let trigger = gpiob_pins;//take over gpiob pins handle
+        let mut trigger = pb.p10.into_push_pull_output();
+        trigger.set_low();//Put OFF initially
 
         //Input capture
-        let ic = InputCapture::configure(&clocks, cx.device.TIM1);
-        ic.enable_cc1ie_interrupt();
-        ic.enable_update_interrupt();
+        #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
+        let ic = {
+            let _ = echo_pin;//captured by tim1 ch1
+            let ic = InputCapture::configure(&clocks, cx.device.TIM1);
+            ic.enable_cc1ie_interrupt();
+            ic.enable_update_interrupt();
+            ic
+        };
+
+        //Pulse width straight from tim1 ccr2
+        #[cfg(feature = "echo-pwm-input")]
+        let pwm_input = {
+            let _ = echo_pin;//both channels capture ti1
+            let pwm_input = PwmInput::configure(&clocks, cx.device.TIM1);
+            pwm_input.enable_interrupt();
+            pwm_input.enable();
+            pwm_input
+        };
+
+        //Echo edges, timed with the cycle counter
+        #[cfg(feature = "echo-exti")]
+        let echo = {
+            let (mut dcb, mut dwt) = (cx.core.DCB, cx.core.DWT);
+            dcb.enable_trace();
+            dwt.enable_cycle_counter();
+            ExtiEcho::new(&clocks, echo_pin, &cx.device.AFIO, &cx.device.EXTI)
+        };
 
         //Led handle
-        let led = led::Led::new(&clocks, cx.device.GPIOC);
+        let led = led::Led::new(pc.p13.into_push_pull_output());
 
         //Usart handle
-        let mut usart = usart1::Usart1::config(&clocks, cx.device.USART1);
-        usart.enable_interrupt();//enable interrupt
+        let usart = usart1::Usart1::config(&clocks, cx.device.USART1, BAUD).unwrap();
+        let (serial, rx, tx) = usart.into_buffered(cx.local.rx_queue, cx.local.tx_queue);//interrupt driven from here on
+        let dma = TxDma::config(&clocks, cx.device.DMA1, cx.local.dma_buf);
 
         //Configure delay
         DelayMs::configure(&clocks, cx.device.TIM4);
@@ -93,16 +310,20 @@ mod app {
         pwm.configure(&clocks);
         pwm.enable();
 
-        pwm.set_servo_duty(15);//initialize servo at Middle pos
+        let servo = pwm.servo(ServoCalibration::SG90, SlewRate::SG90, uptime_ms);//initialize servo at Middle pos
         pwm.set_motor_duty(100);//motors to run at full speed
 
         //Shift Register pins configuration
-        ShiftRegisterPins::configure();
-        ShiftRegisterPins::latch_low();
-        ShiftRegisterPins::clock_low();
-        ShiftRegisterPins::data_low();
+        let shift_register = ShiftRegisterPins::new(
+            pb.p13.into_push_pull_output(),//latch
+            pb.p14.into_push_pull_output(),//data
+            pb.p12.into_push_pull_output(),//clock
+        );
+        let motors = ShiftRegister::new(shift_register, DelayMs);//all lines low
 
         control::spawn().unwrap();
+        telemetry::spawn().unwrap();
+        receive_command::spawn().unwrap();
 
         rtt_init_print!();
         rprintln!("init");
@@ -111,20 +332,48 @@ mod app {
             Shared {
                 command: None,
                 auto: false,
+                #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
                 ov_cnt: 0,
                 distance: None,
+                last_distance: None,
+                echo_pending: false,
+                sweep_angles: Sweep::DEFAULT,
+                unsent_scan: None,
+                sound: SpeedOfSound::FALLBACK,
+                #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
                 ic,
+                tx,
+                dma,
+                ultrasonic_pos: Middle,
+                active: Stop,
+                telemetry_ms: TELEMETRY_MS,
+                pwm,
+                servo,
+                d_stop: D_STOP,
+                speed_limit: protocol::FULL_SPEED,
+                last_rx: 0,
+                link_timeout_ms: LINK_TIMEOUT_MS,
             },
 
             Local {
                 led,
-                usart,
+                decoder: Decoder::new(),
+                line: LineBuffer::new(),
+                air: Fixed::FALLBACK,
+                rx,
+                serial,
                 trigger,
+                motors,
+                #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
                 echo_status: IDLE,
-                pwm,
+                #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
                 t1: 0,
+                #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
                 t2: 0,
-                ultrasonic_pos: Middle,
+                #[cfg(feature = "echo-exti")]
+                echo,
+                #[cfg(feature = "echo-pwm-input")]
+                pwm_input,
             },
         )
     }
@@ -136,168 +385,445 @@ mod app {
         }
     }
 
-    #[task(binds = USART1, local = [usart, led], shared = [auto, command], priority = 3)]
-    fn receive_command(cx: receive_command::Context) {
+    #[task(binds = USART1, local = [serial], priority = 3)]
+    fn usart_interrupt(cx: usart_interrupt::Context) {
+        cx.local.serial.on_interrupt();//move bytes between the data register and the queues
+    }
+
+    #[task(binds = DMA1_CHANNEL4, shared = [dma], priority = 3)]
+    fn tx_done(mut cx: tx_done::Context) {
+        cx.shared.dma.lock(|dma| dma.on_complete());//transfer finished, channel free again
+    }
+
+    #[task(local = [led, decoder, line, rx], shared = [auto, command, tx, telemetry_ms, last_rx, link_timeout_ms], priority = 2)]
+    async fn receive_command(cx: receive_command::Context) {
         rprintln!("command task started");
-        let auto = cx.shared.auto;
-        let command = cx.shared.command;
-        let usart = cx.local.usart;
+        let mut auto = cx.shared.auto;
+        let mut command = cx.shared.command;
+        let mut tx = cx.shared.tx;
+        let mut telemetry_ms = cx.shared.telemetry_ms;
+        let mut last_rx = cx.shared.last_rx;
+        let mut link_timeout_ms = cx.shared.link_timeout_ms;
         let led = cx.local.led;
+        let decoder = cx.local.decoder;
+        let line = cx.local.line;
+        let rx = cx.local.rx;
+        let mut last_byte_ms = 0;
+        let mut lost = 0;
 
-        usart.disable_interrupt();//disable interrupts until finished
-
-        //read and loopback
-        let byte = usart.receive();
-        
-        (auto, command).lock(|auto, command| {
-            match byte {
-                0x41 => {
-                    if *auto {
-                        *auto = false;
-                        led.off();//indication led
-                        *command = Some(Brake);//turning from auto, brake to stop
-                    } else {
-                        *auto = true;//change to auto
-                        led.on();
-                    }
-                },
-                _ => {
-                    if *auto {
-                        //take no command if in auto mode
-                    } else {
-                        match byte {
-                            //set commands according to received
-                            //byte
-                            0x42 => *command = Some(Forward),
-                            0x43 => *command = Some(Reverse),
-                            0x44 => *command = Some(RightTurn),
-                            0x45 => *command = Some(LeftTurn),
-                            0x46 => *command = Some(Brake),
-                            0x47 => *command = Some(Stop),
-                            0x48 => *command = Some(Donut),
-                            _ => {},
+        loop {
+            while let Some(byte) = rx.dequeue() {
+                last_byte_ms = uptime_ms();
+
+                //bytes outside a frame go to the text console
+                if decoder.is_idle() && byte != protocol::START {
+                    if let Some(result) = line.push(byte) {
+                        if console::spawn(result).is_err() {
+                            rprintln!("console busy");
                         }
                     }
-                },
+                    continue;
+                }
+
+                //act only on complete frames that pass validation
+                let (seq, reply) = match decoder.feed(byte) {
+                    Some(Ok(frame)) => {
+                        last_rx.lock(|last_rx| *last_rx = uptime_ms());//any valid frame keeps the link alive
+
+                        let reply = match protocol::request(&frame) {
+                            Ok(request) => {
+                                (&mut auto, &mut command, &mut telemetry_ms, &mut link_timeout_ms).lock(|auto, command, telemetry_ms, link_timeout_ms| {
+                                    let reply = requests::apply(request, auto, command, telemetry_ms, link_timeout_ms);
+                                    if *auto { led.on() } else { led.off() }//indication led
+                                    reply
+                                })
+                            },
+                            Err(reply) => reply,
+                        };
+                        (frame.seq, reply)
+                    },
+                    Some(Err(e)) => {
+                        rprintln!("frame rejected: {:?}", e);
+                        (e.seq(), Reply::Malformed)
+                    },
+                    None => continue,//frame incomplete
+                };
+
+                rprintln!("reply {:?} to {}", reply, seq);
+                let mut buf = [0; MAX_FRAME];
+                let len = reply.frame(seq).encode(&mut buf);
+
+                if !tx.lock(|tx| tx.write_all(&buf[..len])) {//acknowledge the frame
+                    rprintln!("tx queue full, reply dropped");
+                }
+            }
+
+            let lost_now = usart1::rx_overruns().wrapping_add(usart1::rx_dropped());
+            if lost_now != lost {
+                rprintln!("rx lost {} bytes", lost_now.wrapping_sub(lost));
+                lost = lost_now;
             }
-        });
 
-        usart.transmit(byte);//loop back command byte 
-        usart.enable_interrupt();//enable interrupts after finished
+            //the link dropped mid frame, look for a new START and let text through again
+            if !decoder.is_idle() && uptime_ms().wrapping_sub(last_byte_ms) > FRAME_TIMEOUT_MS {
+                rprintln!("partial frame dropped");
+                decoder.reset();
+            }
+
+            Systick::delay(RX_POLL_MS.millis()).await;//rx queue empty, let the isr collect more
+        }
     }
 
-    #[task(local = [pwm, ultrasonic_pos], shared = [auto, command, distance], priority = 1)]
+    #[task(local = [motors], shared = [auto, command, distance, ultrasonic_pos, active, pwm, servo, d_stop, speed_limit, last_rx, link_timeout_ms, sweep_angles, unsent_scan], priority = 1)]
     async fn control(cx: control::Context) {
         rprintln!("control task started");
         let mut auto = cx.shared.auto;
         let mut command = cx.shared.command;
         let mut distance = cx.shared.distance;
-        let pwm = cx.local.pwm;
-        let us_pos = cx.local.ultrasonic_pos;
-
-        let mut dr = 0;//distance in the right direction
-        let mut dl = 0;//distance in the left direction
-
-        drive_motors(&Stop);//start from stop position
+        let mut us_pos = cx.shared.ultrasonic_pos;
+        let mut active = cx.shared.active;
+        let mut pwm = cx.shared.pwm;
+        let mut servo = cx.shared.servo;
+        let mut d_stop = cx.shared.d_stop;
+        let mut speed_limit = cx.shared.speed_limit;
+        let mut last_rx = cx.shared.last_rx;
+        let mut link_timeout_ms = cx.shared.link_timeout_ms;
+        let mut sweep_angles = cx.shared.sweep_angles;
+        let mut unsent_scan = cx.shared.unsent_scan;
+        let motors = cx.local.motors;
+
+        let mut avoid = Avoidance::new();
+        let mut ranging: Ranging<{ ranging::SAMPLES }> = Ranging::new();
+        let mut was_auto = false;
+        let mut run_until: Option<u32> = None;//end of the timed manual move the motors are running, uptime ms
+
+        drive(motors, &mut pwm, &mut active, &Stop, protocol::FULL_SPEED);//start from stop position
         
         loop {
-            auto.lock(|auto| {
-                if *auto {
-                    if trigger::spawn().is_err() {} 
-                    (distance).lock(|distance| {
-                        while let Some(d) = distance {
-                            rprintln!("{}", d);
-
-                            match us_pos {
-                                Right => {
-                                    dr = distance.unwrap();//get dr
-                                    rprintln!("right distance: {}", dr);
-                                    pwm.set_servo_duty(25);//position us left
-                                    rprintln!("moving us to the left");
-                                    DelayMs::delay_ms(1000);
-                                    *us_pos = Left;
-                                },
-                                Left => {
-                                    dl = distance.unwrap();//get dl
-                                    rprintln!("left distance: {}", dl);
-                                    pwm.set_servo_duty(15);//return us postion to middle
-                                    rprintln!("moving us to the middle");
-                                    DelayMs::delay_ms(1000);
-                                    *us_pos = Middle;
-                                    //compare dr & dl; take required action
-                                    if (dr > D_STOP) | (dl > D_STOP) {
-                                        if dr > dl {
-                                            rprintln!("turn rover right");
-                                            drive_motors(&RightTurn);//...turn motors right
-                                        } else {
-                                            rprintln!("turn rover left");
-                                            drive_motors(&LeftTurn);//...turn motors left
-                                        }
-                                    } else {
-                                        rprintln!("reverse");
-                                        drive_motors(&Reverse);//...reverse motors
-                                        rprintln!("move rover right");
-                                        drive_motors(&RightTurn);//...turn motors right
-                                    }
-
-                                    DelayMs::delay_ms(500);//delay a little
-                                },
-                                Middle => {
-                                    if distance.unwrap() <= D_STOP {
-                                        rprintln!("distance < {}", D_STOP);
-                                        drive_motors(&Brake);//...brake motors
-                                        pwm.set_servo_duty(5);//position ultrasonic to the right
-                                        rprintln!("moving us to the right");
-                                        DelayMs::delay_ms(1000);
-                                        *us_pos = Right;
-                                    } else {
-                                        rprintln!("distance > {}", D_STOP);
-                                        unsafe {
-                                            if !MOVING_FORWARD {
-                                                drive_motors(&Forward);//...drive motors forward
-                                                MOVING_FORWARD = true;
-                                            }
-                                        }
-                                    }
-                                },
-                            }
-                            *distance = None;
-                        }
-                    });                    
-                } else {
-                    //manual
-                    command.lock(|command| {
-                        while let Some(c) = command {
-                            rprintln!("driving motor {:?}", c );
+            let d_stop = d_stop.lock(|d_stop| *d_stop);
+            let limit = speed_limit.lock(|speed_limit| *speed_limit);
+            let link_timeout = link_timeout_ms.lock(|link_timeout_ms| *link_timeout_ms);
+            let mut sweep_due = false;
 
-                            drive_motors(c);
+            let is_auto = auto.lock(|auto| *auto);
 
-                            *command = None;//update command to None
+            if is_auto && !was_auto {
+                if run_until.take().is_some() {
+                    drive(motors, &mut pwm, &mut active, &Stop, limit);//a timed manual move ends with manual mode
+                }
+                avoid = Avoidance::new();//start over looking ahead
+                ranging.clear();
+                turn_servo(&mut servo, &mut us_pos, AHEAD, None).await;//the console may have left the servo anywhere
+            }
+            was_auto = is_auto;
+
+            if is_auto {
+                if trigger::spawn().is_err() {} 
+                let decision = distance.lock(|distance| {
+                    while let Some(range) = distance.take() {
+                        let filtered = match ranging.push(range) {
+                            Some(filtered) => filtered,
+                            None => continue,//more echoes to take here
+                        };
+                        rprintln!("ahead: {:?} ({}%)", filtered.range, filtered.confidence);
+                        if filtered.valid {
+                            return Some(avoid.step(filtered.range, d_stop));
                         }
-                    });
+                    }
+                    None//readings disagree or still coming, measure again
+                });
+
+                if let Some(decision) = decision {
+                    act(motors, &mut pwm, &mut active, &mut auto, &decision, limit).await;
+                    sweep_due = decision.sweep;
                 }
-            });
+            } else {
+                //manual
+                let (lost, next) = (&mut command, &mut active, &mut last_rx).lock(|command, active, last_rx| {
+                    let silent_ms = uptime_ms().wrapping_sub(*last_rx);
+                    let lost = requests::link_lost(silent_ms, link_timeout, command.is_some(), active);
+                    (lost.then_some(silent_ms), command.take())
+                });
+
+                //failsafe: stop if the link has been silent too long
+                if let Some(silent_ms) = lost {
+                    rprintln!("link lost for {} ms, stopping", silent_ms);
+                    run_until = None;
+                    drive(motors, &mut pwm, &mut active, &Stop, limit);
+                }
+
+                //a new command replaces a timed move still running
+                if let Some(c) = next {
+                    rprintln!("driving motor {:?}", c );
+                    run_until = drive(motors, &mut pwm, &mut active, &c, limit).map(|ms| uptime_ms().wrapping_add(ms));
+                } else if run_until.is_some_and(reached) {
+                    run_until = None;
+                    drive(motors, &mut pwm, &mut active, &Stop, limit);
+                }
+            }
+
+            if sweep_due {
+                let angles = sweep_angles.lock(|sweep_angles| *sweep_angles);
+                ranging.clear();
+
+                //switched to manual while sweeping, the operator drives from here
+                let map = match sweep(&mut servo, &mut us_pos, &mut distance, &mut auto, true, &angles).await {
+                    Some(map) => map,
+                    None => continue,
+                };
+                let decision = avoid.choose(&map, d_stop);
+                unsent_scan.lock(|unsent_scan| *unsent_scan = Some(map));
+
+                (&mut servo, &mut us_pos).lock(|servo, us_pos| {
+                    servo.set_angle(AHEAD, uptime_ms());//return us postion to middle, settles while turning
+                    *us_pos = Middle;
+                });
+                act(motors, &mut pwm, &mut active, &mut auto, &decision, limit).await;
+                in_position(&mut servo).await;//normally there by the end of the turn
+            }
         }
     }
 
 
-    #[task(local = [trigger], shared = [distance], priority = 2)]
+    #[task(local = [air], shared = [auto, command, last_distance, distance, sound, ultrasonic_pos, active, tx, dma, pwm, servo, d_stop, speed_limit, last_rx, link_timeout_ms, sweep_angles, unsent_scan], priority = 2)]
+    async fn console(cx: console::Context, result: Result<ShellCommand, ShellError>) {
+        let mut auto = cx.shared.auto;
+        let mut command = cx.shared.command;
+        let mut last_distance = cx.shared.last_distance;
+        let mut distance = cx.shared.distance;
+        let mut us_pos = cx.shared.ultrasonic_pos;
+        let mut active = cx.shared.active;
+        let mut tx = cx.shared.tx;
+        let mut dma = cx.shared.dma;
+        let mut pwm = cx.shared.pwm;
+        let mut servo = cx.shared.servo;
+        let mut d_stop = cx.shared.d_stop;
+        let mut speed_limit = cx.shared.speed_limit;
+        let mut last_rx = cx.shared.last_rx;
+        let mut link_timeout_ms = cx.shared.link_timeout_ms;
+        let mut sound = cx.shared.sound;
+        let mut sweep_angles = cx.shared.sweep_angles;
+        let mut unsent_scan = cx.shared.unsent_scan;
+        let air = cx.local.air;
+        let mut out: String<DMA_LEN> = String::new();//whole reply goes out as one transfer
+
+        rprintln!("console {:?}", result);
+        let is_auto = auto.lock(|auto| *auto);
+
+        match result {
+            Ok(ShellCommand::Status) => {
+                write!(out, "mode: {}\r\n", if is_auto { "auto" } else { "manual" }).ok();
+                match last_distance.lock(|last_distance| *last_distance) {
+                    Some(range) => write!(out, "distance: {}\r\n", range).ok(),
+                    None => write!(out, "distance: -\r\n").ok(),
+                };
+                write!(out, "sensor: {:?}\r\n", us_pos.lock(|us_pos| *us_pos)).ok();
+                let (angle, calibration, rate) = servo.lock(|servo| (servo.angle(), servo.calibration(), servo.rate()));
+                write!(out, "servo: {} deg, pulses {}/{}/{} us, {} deg/s\r\n",
+                       angle, calibration.min_us(), calibration.centre_us(), calibration.max_us(), rate.deg_per_s()).ok();
+                let angles = sweep_angles.lock(|sweep_angles| *sweep_angles);
+                write!(out, "sweep: {} to {} deg every {} deg\r\n", angles.from(), angles.to(), angles.step()).ok();
+                write!(out, "command: {:?}\r\n", active.lock(|active| *active)).ok();
+                write!(out, "dstop: {}\r\n", d_stop.lock(|d_stop| *d_stop)).ok();
+                write!(out, "speed: {}%\r\n", speed_limit.lock(|speed_limit| *speed_limit)).ok();
+                write!(out, "air: {} C", air.temperature_dc / 10).ok();
+                if let Some(rh) = air.humidity_pct {
+                    write!(out, ", {}% rh", rh).ok();
+                }
+                write!(out, ", sound at {} m/s\r\n", sound.lock(|sound| sound.mm_per_s()) / 1000).ok();
+                write!(out, "link: {} ms since last frame, timeout {} ms\r\n",
+                       uptime_ms().wrapping_sub(last_rx.lock(|last_rx| *last_rx)),
+                       link_timeout_ms.lock(|link_timeout_ms| *link_timeout_ms)).ok();
+                write!(out, "rx: {} overruns, {} dropped\r\n", usart1::rx_overruns(), usart1::rx_dropped()).ok();
+                write!(out, "uptime: {} ms\r\n", uptime_ms()).ok();
+            },
+            Ok(ShellCommand::SetDStop(d)) => {
+                d_stop.lock(|d_stop| *d_stop = d);
+                write!(out, "dstop set to {}\r\n", d).ok();
+            },
+            Ok(ShellCommand::SetLinkTimeout(ms)) => {
+                link_timeout_ms.lock(|link_timeout_ms| *link_timeout_ms = ms);
+                write!(out, "link timeout set to {} ms\r\n", ms).ok();
+            },
+            Ok(ShellCommand::SetTemperature(c)) => {
+                air.temperature_dc = i16::from(c) * 10;
+                let speed = sound.lock(|sound| {
+                    *sound = SpeedOfSound::from_source(air);
+                    sound.mm_per_s()
+                });
+                write!(out, "air temperature set to {} C, sound at {} m/s\r\n", c, speed / 1000).ok();
+            },
+            Ok(ShellCommand::SetHumidity(rh)) => {
+                air.humidity_pct = Some(rh);
+                let speed = sound.lock(|sound| {
+                    *sound = SpeedOfSound::from_source(air);
+                    sound.mm_per_s()
+                });
+                write!(out, "humidity set to {}%, sound at {} m/s\r\n", rh, speed / 1000).ok();
+            },
+            Ok(ShellCommand::SetSweep(angles)) => {
+                sweep_angles.lock(|sweep_angles| *sweep_angles = angles);
+                write!(out, "sweep {} to {} deg every {} deg\r\n", angles.from(), angles.to(), angles.step()).ok();
+            },
+            Ok(ShellCommand::SetSlew(rate)) => {
+                servo.lock(|servo| servo.set_rate(rate));
+                write!(out, "servo slews at {} deg/s\r\n", rate.deg_per_s()).ok();
+            },
+            Ok(ShellCommand::Speed(speed)) => {
+                speed_limit.lock(|speed_limit| *speed_limit = speed);
+                (&mut pwm, &mut active).lock(|pwm, active| {
+                    pwm.set_motor_duty(u16::from(active.speed().min(speed)));//apply to running motors
+                });
+                write!(out, "speed limited to {}%\r\n", speed).ok();
+            },
+            Ok(ShellCommand::Servo(..)) | Ok(ShellCommand::Scan) if is_auto => {
+                write!(out, "servo is in use by auto mode\r\n").ok();
+            },
+            Ok(ShellCommand::Servo(angle, rate)) => {
+                turn_servo(&mut servo, &mut us_pos, angle, rate).await;
+                write!(out, "servo at {} deg\r\n", angle).ok();
+            },
+            Ok(ShellCommand::SetServoCalibration(calibration)) => {
+                servo.lock(|servo| servo.set_calibration(calibration, uptime_ms()));
+                write!(out, "servo pulses {}/{}/{} us\r\n", calibration.min_us(), calibration.centre_us(), calibration.max_us()).ok();
+            },
+            Ok(ShellCommand::Scan) => {
+                command.lock(|command| *command = Some(Stop));//hold still while scanning
+
+                let angles = sweep_angles.lock(|sweep_angles| *sweep_angles);
+                let map = match sweep(&mut servo, &mut us_pos, &mut distance, &mut auto, false, &angles).await {
+                    Some(map) => map,
+                    None => {
+                        write!(out, "scan abandoned, auto mode started\r\n").ok();//auto mode has the servo now
+                        print(&mut tx, &mut dma, &out).await;
+                        return;
+                    },
+                };
+                for (angle, d) in &map {
+                    write!(out, "{:3} deg: {}\r\n", angle, d).ok();
+                    print(&mut tx, &mut dma, &out).await;//a long sweep does not fit one transfer
+                    out.clear();
+                }
+                unsent_scan.lock(|unsent_scan| *unsent_scan = Some(map));
+
+                (&mut servo, &mut us_pos).lock(|servo, us_pos| {
+                    servo.set_angle(AHEAD, uptime_ms());//return us postion to middle
+                    *us_pos = Middle;
+                });
+            },
+            Ok(ShellCommand::Help) => {
+                write!(out, "{}\r\n", shell::HELP).ok();
+            },
+            Err(ShellError::Unknown) => {
+                write!(out, "unknown command, try help\r\n").ok();
+            },
+            Err(ShellError::BadArgument) => {
+                write!(out, "bad argument\r\n").ok();
+            },
+            Err(ShellError::TooLong) => {
+                write!(out, "line too long\r\n").ok();
+            },
+        }
+
+        print(&mut tx, &mut dma, &out).await;
+    }
+
+    #[task(shared = [auto, last_distance, ultrasonic_pos, active, tx, telemetry_ms, last_rx, link_timeout_ms, unsent_scan], priority = 2)]
+    async fn telemetry(cx: telemetry::Context) {
+        rprintln!("telemetry task started");
+        let mut auto = cx.shared.auto;
+        let mut last_distance = cx.shared.last_distance;
+        let mut us_pos = cx.shared.ultrasonic_pos;
+        let mut active = cx.shared.active;
+        let mut tx = cx.shared.tx;
+        let mut telemetry_ms = cx.shared.telemetry_ms;
+        let mut last_rx = cx.shared.last_rx;
+        let mut link_timeout_ms = cx.shared.link_timeout_ms;
+        let mut unsent_scan = cx.shared.unsent_scan;
+
+        let mut buf = [0; MAX_FRAME];
+        let mut seq: u8 = 0;
+
+        loop {
+            let period = telemetry_ms.lock(|telemetry_ms| *telemetry_ms);
+            if period == 0 {
+                Systick::delay(100.millis()).await;//telemetry off, check again later
+                continue;
+            }
+
+            let status = Telemetry {
+                auto: auto.lock(|auto| *auto),
+                distance: last_distance.lock(|last_distance| *last_distance).and_then(|range| range.distance()).map(|d| d.cm().min(u32::from(u16::MAX - 1)) as u16),
+                position: us_pos.lock(|us_pos| *us_pos),
+                command: active.lock(|active| *active),
+                uptime_ms: uptime_ms(),
+                link_timeout_ms: link_timeout_ms.lock(|link_timeout_ms| *link_timeout_ms),
+                link_age_ms: uptime_ms()
+                    .wrapping_sub(last_rx.lock(|last_rx| *last_rx))
+                    .min(u32::from(u16::MAX)) as u16,
+            };
+
+            let len = status.frame().with_seq(seq).encode(&mut buf);
+            seq = seq.wrapping_add(1);
+            if !tx.lock(|tx| tx.write_all(&buf[..len])) {
+                rprintln!("tx queue full, telemetry dropped");//never send a partial frame
+            }
+
+            //a new sweep follows as scan frames
+            if let Some(map) = unsent_scan.lock(|unsent_scan| unsent_scan.take()) {
+                let count = map.len() as u8;
+                for (i, chunk) in map.chunks(protocol::SCAN_POINTS).enumerate() {
+                    let mut points = [(0, 0); protocol::SCAN_POINTS];
+                    for (point, &(angle, d)) in points.iter_mut().zip(chunk) {
+                        *point = (angle as u8, d.cm().min(u32::from(u16::MAX)) as u16);
+                    }
+
+                    if let Some(scan) = Scan::new((i * protocol::SCAN_POINTS) as u8, count, &points[..chunk.len()]) {
+                        let len = scan.frame().with_seq(seq).encode(&mut buf);
+                        seq = seq.wrapping_add(1);
+                        if !tx.lock(|tx| tx.write_all(&buf[..len])) {
+                            rprintln!("tx queue full, scan dropped");
+                        }
+                    }
+                }
+            }
+
+            Systick::delay(u32::from(period).millis()).await;
+        }
+    }
+
+    #[task(local = [trigger], shared = [distance, last_distance, echo_pending], priority = 2)]
     async fn trigger(cx: trigger::Context) {
         rprintln!("trigger task started");
         let trigger = cx.local.trigger;
-        let mut _distance = cx.shared.distance;
+        let mut distance = cx.shared.distance;
+        let mut last_distance = cx.shared.last_distance;
+        let mut echo_pending = cx.shared.echo_pending;
 
-        trigger.trigger_low();
+        echo_pending.lock(|echo_pending| *echo_pending = true);
+
+        trigger.set_low();
         DelayUs::delay_us(2);
 
-        trigger.trigger_high();
+        trigger.set_high();
         DelayUs::delay_us(10);
 
-        trigger.trigger_low();
+        trigger.set_low();
 
-        Systick::delay(200.millis()).await;
+        Systick::delay(ECHO_DEADLINE_MS.millis()).await;
+        if echo_pending.lock(|echo_pending| core::mem::replace(echo_pending, false)) {
+            (&mut distance, &mut last_distance).lock(|distance, last_distance| {
+                *distance = Some(Range::NoEcho);
+                *last_distance = *distance;
+            });
+            rtic::pend(ECHO_INTERRUPT);//the echo task resets its measurement
+            rprintln!("no echo");
+        }
+
+        Systick::delay((MEASURE_MS - ECHO_DEADLINE_MS).millis()).await;//lets the echo of this ping die out
     }
 
+    #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
     #[task(binds = TIM1_UP, shared = [ic, ov_cnt], priority = 4)]
     fn overflow(cx: overflow::Context) {
         let ic = cx.shared.ic;
@@ -314,7 +840,8 @@ mod app {
         });
     }
 
-    #[task(binds = TIM1_CC, shared = [ic, ov_cnt, distance], local = [echo_status, t1, t2], priority = 3)]
+    #[cfg(not(any(feature = "echo-exti", feature = "echo-pwm-input")))]
+    #[task(binds = TIM1_CC, shared = [ic, ov_cnt, distance, last_distance, sound, echo_pending], local = [echo_status, t1, t2], priority = 3)]
     fn time_capture(cx: time_capture::Context) {
         rprint!("time:\t");
         let mut ic = cx.shared.ic;
@@ -323,8 +850,18 @@ mod app {
         let t2 = cx.local.t2;
         let mut ov_cnt = cx.shared.ov_cnt;
         let mut distance = cx.shared.distance;
+        let mut last_distance = cx.shared.last_distance;
+        let sound = cx.shared.sound;
+        let echo_pending = cx.shared.echo_pending;
+
+        if !ic.lock(|ic| ic.is_capture()) {
+            rprintln!("timed out");//pended by trigger at the deadline
+            ic.lock(|ic| ic.reset());
+            *status = IDLE;
+            return;
+        }
 
-        (distance).lock(|distance| {
+        (&mut distance, &mut last_distance, sound, echo_pending).lock(|distance, last_distance, sound, echo_pending| {
             match status {
                 IDLE => {
                     rprintln!("idle");
@@ -344,20 +881,61 @@ mod app {
                         ic.disable();
                     });
                     *status = IDLE;//update status to idle
-                    let mut OF = 0;
-
-                    ov_cnt.lock(|ov_cnt| OF = *ov_cnt );
-                    let T = *t2 + OF*(65535) - *t1;
-                    let d = (T as f32)*(0.034/2.);
-                    *distance = Some(d.floor() as u32);
-                    rprintln!("{}", distance.unwrap());
+                    let overflows = ov_cnt.lock(|ov_cnt| *ov_cnt);
+                    *distance = Some(sound.range(pulse_us(*t1, *t2, overflows)));
+                    *last_distance = *distance;
+                    *echo_pending = false;
+                    rprintln!("{:?}", distance.unwrap());
                 },
             }
         });
     }
-}
-
-
 
+    #[cfg(feature = "echo-pwm-input")]
+    #[task(binds = TIM1_CC, local = [pwm_input], shared = [distance, last_distance, sound, echo_pending], priority = 3)]
+    fn pulse_width(cx: pulse_width::Context) {
+        let pwm_input = cx.local.pwm_input;
+        let distance = cx.shared.distance;
+        let last_distance = cx.shared.last_distance;
+        let echo_pending = cx.shared.echo_pending;
+        let mut sound = cx.shared.sound;
+
+        //nothing to reset when pended at the deadline, the next rising edge starts over
+        if pwm_input.is_capture() {
+            let range = sound.lock(|sound| sound.range(u32::from(pwm_input.read_pulse())));
+            pwm_input.clear_capture();
+            (distance, last_distance, echo_pending).lock(|distance, last_distance, echo_pending| {
+                *distance = Some(range);
+                *last_distance = *distance;
+                *echo_pending = false;
+            });
+            rprintln!("{:?}", range);
+        }
+    }
 
+    ///echo on pa8 raises exti line 8. Moving it to another pin means binding its exti interrupt here
+    #[cfg(feature = "echo-exti")]
+    #[task(binds = EXTI9_5, local = [echo], shared = [distance, last_distance, sound, echo_pending], priority = 3)]
+    fn echo_edge(cx: echo_edge::Context) {
+        let echo = cx.local.echo;
+        let distance = cx.shared.distance;
+        let last_distance = cx.shared.last_distance;
+        let echo_pending = cx.shared.echo_pending;
+        let mut sound = cx.shared.sound;
+
+        if !echo.is_pending() {
+            echo.reset();//pended by trigger at the deadline
+            return;
+        }
 
+        if let Some(pulse) = echo.on_interrupt() {
+            let range = sound.lock(|sound| sound.range(pulse));
+            (distance, last_distance, echo_pending).lock(|distance, last_distance, echo_pending| {
+                *distance = Some(range);
+                *last_distance = *distance;
+                *echo_pending = false;
+            });
+            rprintln!("{:?}", range);
+        }
+    }
+}